@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+/// Exponential backoff with jitter, used to space out attempts of a failed task.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    /// Create a new backoff.
+    ///
+    /// # Arguments
+    ///
+    /// `base` - The longest delay before the first retry.
+    /// `max` - The longest delay before any retry.
+    ///
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max }
+    }
+
+    /// The delay before retrying after the given number of failed attempts.
+    ///
+    /// The delay is chosen at random between half and all of `base * 2^(attempts - 1)`, capped at `max`, so tasks
+    /// that failed together don't all retry together.
+    ///
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        let ceiling = self.base.saturating_mul(factor).min(self.max);
+        ceiling.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_exponentially_with_jitter() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(3600));
+        for (attempts, ceiling) in [(1, 1), (2, 2), (3, 4), (4, 8)] {
+            let delay = backoff.delay(attempts);
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?} not within {ceiling:?}");
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_at_max() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        assert!(backoff.delay(40) <= Duration::from_secs(60));
+    }
+}
@@ -1,4 +1,10 @@
+mod backoff;
+mod queue_selector;
+mod rate_limiter;
 mod task_executor;
 mod thread_main;
 
-pub use task_executor::*;
\ No newline at end of file
+pub use backoff::*;
+pub use queue_selector::*;
+pub use rate_limiter::*;
+pub use task_executor::*;
@@ -0,0 +1,132 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Chooses which queues the executor pulls its next task from.
+///
+/// Queues are preferred in the order they're configured unless they're given weights, in which case they take turns
+/// in proportion to their weights using smooth weighted round-robin. Queues with a concurrency limit are skipped while
+/// that many of their tasks are being processed.
+///
+#[derive(Debug, Clone)]
+pub struct QueueSelector {
+    queues: Vec<String>,
+    weights: HashMap<String, u32>,
+    limits: HashMap<String, usize>,
+    credits: HashMap<String, i64>,
+}
+
+impl QueueSelector {
+    /// Create a new queue selector.
+    ///
+    /// # Arguments
+    ///
+    /// `queues` - The names of the queues to pull from, in order of preference.
+    /// `weights` - How many turns each queue gets relative to the others. Queues without a weight get 1.
+    /// `limits` - How many tasks from each queue can be processed at once. Queues without a limit aren't limited.
+    ///
+    pub fn new(queues: Vec<String>, weights: HashMap<String, u32>, limits: HashMap<String, usize>) -> Self {
+        Self { queues, weights, limits, credits: HashMap::new() }
+    }
+
+    /// The names of the queues to pull from, in their configured order.
+    pub fn queues(&self) -> &[String] {
+        &self.queues
+    }
+
+    /// The queues to pull the next task from, in order of preference.
+    ///
+    /// # Arguments
+    ///
+    /// `busy` - How many tasks from each queue are being processed.
+    ///
+    pub fn candidates(&self, busy: &HashMap<String, usize>) -> Vec<String> {
+        let mut candidates = self.queues
+            .iter()
+            .filter(|queue| match self.limits.get(*queue) {
+                Some(limit) => busy.get(*queue).copied().unwrap_or_default() < *limit,
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if !self.weights.is_empty() {
+            // Stable, so queues with the same credit keep their configured order.
+            candidates.sort_by_key(|queue| Reverse(self.credit(queue) + self.weight(queue)));
+        }
+        candidates.into_iter().cloned().collect()
+    }
+
+    /// Record that a task was pulled from `queue`, so the other queues get their turns.
+    pub fn advance(&mut self, queue: &str) {
+        if self.weights.is_empty() {
+            return;
+        }
+
+        let total = self.queues.iter().map(|queue| self.weight(queue)).sum::<i64>();
+        for queue in &self.queues {
+            let weight = self.weight(queue);
+            *self.credits.entry(queue.clone()).or_default() += weight;
+        }
+        *self.credits.entry(queue.to_string()).or_default() -= total;
+    }
+
+    fn weight(&self, queue: &str) -> i64 {
+        self.weights.get(queue).copied().unwrap_or(1) as i64
+    }
+
+    fn credit(&self, queue: &str) -> i64 {
+        self.credits.get(queue).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queues(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn candidates_take_turns_by_weight() {
+        let weights = HashMap::from([("critical".to_string(), 5), ("default".to_string(), 2), ("low".to_string(), 1)]);
+        let mut selector = QueueSelector::new(queues(&["critical", "default", "low"]), weights, HashMap::new());
+
+        let mut turns = HashMap::<String, usize>::new();
+        for _ in 0..80 {
+            let first = selector.candidates(&HashMap::new())[0].clone();
+            selector.advance(&first);
+            *turns.entry(first).or_default() += 1;
+        }
+        assert_eq!(turns["critical"], 50);
+        assert_eq!(turns["default"], 20);
+        assert_eq!(turns["low"], 10);
+    }
+
+    #[test]
+    fn advancing_charges_the_queue_the_task_came_from() {
+        let weights = HashMap::from([("critical".to_string(), 1), ("default".to_string(), 1)]);
+        let mut selector = QueueSelector::new(queues(&["critical", "default"]), weights, HashMap::new());
+
+        // The preferred queue was empty, so the task came from the next one.
+        selector.advance("default");
+        assert_eq!(selector.candidates(&HashMap::new()), queues(&["critical", "default"]));
+    }
+
+    #[test]
+    fn candidates_keep_configured_order_without_weights() {
+        let mut selector = QueueSelector::new(queues(&["critical", "default"]), HashMap::new(), HashMap::new());
+        selector.advance("critical");
+        assert_eq!(selector.candidates(&HashMap::new()), queues(&["critical", "default"]));
+    }
+
+    #[test]
+    fn candidates_skip_queues_at_their_limit() {
+        let limits = HashMap::from([("low".to_string(), 2)]);
+        let selector = QueueSelector::new(queues(&["default", "low"]), HashMap::new(), limits);
+
+        let busy = HashMap::from([("low".to_string(), 1)]);
+        assert_eq!(selector.candidates(&busy), queues(&["default", "low"]));
+        let busy = HashMap::from([("low".to_string(), 2)]);
+        assert_eq!(selector.candidates(&busy), queues(&["default"]));
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How many tasks with a definition can be started within a period.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The most tasks started per period, which is also how many can start in a burst.
+    pub limit: u32,
+
+    /// The length of the period in seconds, 1 by default.
+    #[serde(default = "default_period")]
+    pub period: u64,
+}
+
+fn default_period() -> u64 {
+    1
+}
+
+/// Token-bucket rate limits on starting tasks, keyed by task definition.
+///
+/// Each definition with a limit has a bucket holding up to `limit` tokens, refilled evenly over `period`. Starting a
+/// task takes a token, and tasks whose bucket is empty have to wait for the next one.
+///
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: HashMap<String, RateLimit>,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter.
+    ///
+    /// # Arguments
+    ///
+    /// `limits` - The rate limits, keyed by task definition. Definitions without a limit aren't limited.
+    ///
+    pub fn new(limits: HashMap<String, RateLimit>) -> Self {
+        Self { limits, buckets: HashMap::new() }
+    }
+
+    /// Take a token to start a task with the given definition.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the task can start now, otherwise how long until a token is available.
+    ///
+    pub fn acquire(&mut self, definition: &str, now: Instant) -> Option<Duration> {
+        let limit = self.limits.get(definition)?;
+        let capacity = limit.limit as f64;
+        let per_token = Duration::from_secs(limit.period).div_f64(capacity.max(1.0));
+
+        let bucket = self.buckets
+            .entry(definition.to_string())
+            .or_insert(TokenBucket { tokens: capacity, updated_at: now });
+        let refilled = now.saturating_duration_since(bucket.updated_at).as_secs_f64() / per_token.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(per_token.mul_f64(1.0 - bucket.tokens))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(definition: &str, limit: u32, period: u64) -> RateLimiter {
+        RateLimiter::new(HashMap::from([(definition.to_string(), RateLimit { limit, period })]))
+    }
+
+    #[test]
+    fn acquire_allows_a_burst_up_to_the_limit() {
+        let mut limiter = limiter("SendSms", 10, 1);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire("SendSms", now), None);
+        }
+        assert_eq!(limiter.acquire("SendSms", now), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn acquire_refills_tokens_over_the_period() {
+        let mut limiter = limiter("SendSms", 2, 10);
+        let now = Instant::now();
+        limiter.acquire("SendSms", now);
+        limiter.acquire("SendSms", now);
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.acquire("SendSms", later), Some(Duration::from_secs(3)));
+        let later = now + Duration::from_secs(5);
+        assert_eq!(limiter.acquire("SendSms", later), None);
+    }
+
+    #[test]
+    fn acquire_doesnt_limit_other_definitions() {
+        let mut limiter = limiter("SendSms", 1, 1);
+        let now = Instant::now();
+        limiter.acquire("SendSms", now);
+        assert!(limiter.acquire("SendSms", now).is_some());
+        assert_eq!(limiter.acquire("SendEmail", now), None);
+    }
+}
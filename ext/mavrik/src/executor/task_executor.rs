@@ -1,29 +1,74 @@
 use crate::executor::thread_main::rb_thread_main;
+use crate::executor::{Backoff, QueueSelector, RateLimiter};
 use crate::mavrik::MavrikOptions;
 use crate::messaging::{Task, TaskId, TaskResult};
-use crate::rb::util::in_ruby;
+use crate::rb::util::{in_ruby, module_mavrik};
 use crate::service::ServiceTask;
-use crate::store::ProcessStore;
-use log::{debug, error};
+use crate::store::{ProcessStore, QueryStore, DEFAULT_LEASE_TIMEOUT};
+use anyhow::anyhow;
+use log::{debug, error, info, warn};
 use magnus::value::ReprValue;
-use std::collections::HashMap;
+use magnus::{ArgList, Module, RClass};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::pending;
+use std::time::Duration;
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, sleep, sleep_until, Instant, Interval, MissedTickBehavior};
+
+/// How often the executor checks for Ruby threads that have died and autoscales its thread pool.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The shortest time between renewing the leases of tasks being processed.
+const MIN_RENEW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait before dequeuing again after the store failed to hand out a task.
+const DEQUEUE_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 /// ID associated with a Ruby thread created by the task executor.
 pub type ThreadId = usize;
 
+/// ID the task executor gives each task it hands to a Ruby thread, so a task delivered twice isn't mixed up.
+pub type LeaseId = u64;
+
 #[derive(Debug)]
 pub enum ThreadMessage {
     ThreadReady(ThreadId),
-    TaskComplete((TaskId, TaskResult))
+    TaskComplete((LeaseId, TaskResult))
+}
+
+/// A request sent to the task executor from outside of it.
+#[derive(Debug)]
+pub enum ExecutorCommand {
+    /// Stop processing a task, if it's being processed.
+    CancelTask(TaskId),
+
+    /// Get the size of the thread pool.
+    GetPoolState { reply: oneshot::Sender<PoolState> },
+
+    /// Resize the thread pool, replying with its new size. The pool autoscales if `max_threads` is larger.
+    ResizePool { min_threads: usize, max_threads: usize, reply: oneshot::Sender<PoolState> },
+}
+
+/// The size of the task executor's Ruby thread pool.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PoolState {
+    pub threads: usize, // Threads taking new tasks
+    pub busy_threads: usize, // Threads processing a task, including those finishing up before being removed
+    pub min_threads: usize,
+    pub max_threads: usize,
 }
 
 #[derive(Debug)]
 pub enum TaskOutputKind {
     ThreadReady(ThreadId),
-    TaskComplete((TaskId, TaskResult)),
-    NextTask((TaskId, Task))
+    TaskComplete((LeaseId, TaskResult)),
+    NextTask((TaskId, String, Task)),
+    Command(ExecutorCommand),
+    TimeOutTask(LeaseId),
+    SuperviseThreads,
+    RenewLeases
 }
 
 impl From<ThreadMessage> for TaskOutputKind {
@@ -37,16 +82,39 @@ impl From<ThreadMessage> for TaskOutputKind {
 
 pub struct ThreadTableEntry {
     thread: magnus::Thread,
-    task_tx: mpsc::Sender<(TaskId, Task)>,
+    task_tx: mpsc::Sender<(LeaseId, TaskId, Task)>,
 }
 
+/// A task sent to a Ruby thread that hasn't completed yet.
+pub struct InFlightTask {
+    task_id: TaskId,
+    thread_id: ThreadId,
+    task: Task,
+    queue: String,
+    deadline: Option<Instant>, // When the task times out, cleared once the thread has been interrupted
+}
 
 pub struct TaskExecutor<Store> {
     store: Store,
+    queues: QueueSelector,
+    backoff: Backoff,
+    rate_limiter: RateLimiter,
+    default_timeout: Option<Duration>,
+    in_flight: HashMap<LeaseId, InFlightTask>,
+    next_lease_id: LeaseId,
+    cancelling: HashSet<TaskId>,
     thread_table: HashMap<ThreadId, ThreadTableEntry>,
+    retiring_threads: HashSet<ThreadId>, // Threads removed from the pool that are finishing their current task
+    next_thread_id: ThreadId,
+    min_threads: usize,
+    max_threads: usize,
+    thread_restarts: usize,
+    messages_tx: mpsc::Sender<ThreadMessage>,
     messages_rx: mpsc::Receiver<ThreadMessage>,
-    task_buf: Vec<(TaskId, Task)>,
+    commands_rx: mpsc::UnboundedReceiver<ExecutorCommand>,
     thread_ready_buf: Vec<ThreadId>,
+    supervise_interval: Interval,
+    renew_interval: Interval, // Renews leases well before they expire, so long-running tasks aren't dequeued again
 }
 
 impl<Store> TaskExecutor<Store> {
@@ -55,78 +123,477 @@ impl<Store> TaskExecutor<Store> {
     /// # Arguments
     ///
     /// `options` - Options for configuring the task executor.
-    /// `params` - Values for constructing the task executor.
+    /// `store` - The store tasks are dequeued from and results are published to.
+    /// `commands_rx` - Receives commands, such as tasks to stop processing and thread pool resizes.
     ///
-    pub fn new(options: &MavrikOptions, store: Store) -> Result<Self, anyhow::Error> {
+    pub fn new(
+        options: &MavrikOptions,
+        store: Store,
+        commands_rx: mpsc::UnboundedReceiver<ExecutorCommand>,
+    ) -> Result<Self, anyhow::Error> {
         let rb_thread_count = options.rb_thread_count.unwrap_or(4);
+        let rb_thread_max_count = options.rb_thread_max_count.unwrap_or(rb_thread_count).max(rb_thread_count);
+        let queues = QueueSelector::new(
+            options.queues.clone().unwrap_or_else(|| vec!["default".to_string()]),
+            options.queue_weights.clone().unwrap_or_default(),
+            options.queue_concurrency.clone().unwrap_or_default(),
+        );
+        let backoff = Backoff::new(
+            Duration::from_secs(options.retry_base_delay.unwrap_or(1)),
+            Duration::from_secs(options.retry_max_delay.unwrap_or(3600)),
+        );
+        let rate_limiter = RateLimiter::new(options.rate_limits.clone().unwrap_or_default());
+        let default_timeout = options.task_timeout.map(Duration::from_secs);
+        let in_flight = HashMap::new();
+        let cancelling = HashSet::new();
 
-        let mut thread_table = HashMap::new();
-        let (messages_tx, messages_rx) = mpsc::channel(rb_thread_count);
-        let task_buf = Vec::new();
+        let (messages_tx, messages_rx) = mpsc::channel(rb_thread_max_count);
+        let thread_table = (0..rb_thread_count)
+            .map(|thread_id| (thread_id, spawn_thread(thread_id, &messages_tx)))
+            .collect();
         let thread_ready_buf = Vec::new();
-        
-        in_ruby(|r| {
-            for thread_id in 0..rb_thread_count {
-                let (task_tx, task_rx) = mpsc::channel(1);
-                let messages_tx = messages_tx.clone();
-                let thread = r.thread_create_from_fn(move |r| rb_thread_main(r, thread_id, messages_tx, task_rx));
 
-                thread_table.insert(thread_id, ThreadTableEntry { thread, task_tx });
+        let mut supervise_interval = interval(SUPERVISE_INTERVAL);
+        supervise_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let lease_timeout = options.lease_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_LEASE_TIMEOUT);
+        let mut renew_interval = interval((lease_timeout / 3).max(MIN_RENEW_INTERVAL));
+        renew_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
+            store,
+            queues,
+            backoff,
+            rate_limiter,
+            default_timeout,
+            in_flight,
+            next_lease_id: 0,
+            cancelling,
+            thread_table,
+            retiring_threads: HashSet::new(),
+            next_thread_id: rb_thread_count,
+            min_threads: rb_thread_count,
+            max_threads: rb_thread_max_count,
+            thread_restarts: 0,
+            messages_tx,
+            messages_rx,
+            commands_rx,
+            thread_ready_buf,
+            supervise_interval,
+            renew_interval,
+        })
+    }
+
+    /// The in-flight task that times out soonest, along with when it times out.
+    fn next_timeout(&self) -> Option<(Instant, LeaseId)> {
+        self.in_flight
+            .iter()
+            .filter_map(|(lease_id, in_flight)| in_flight.deadline.map(|deadline| (deadline, *lease_id)))
+            .min()
+    }
+
+    /// The queues to pull the next task from, in order of preference, leaving out those at their concurrency limit.
+    fn next_queues(&self) -> Vec<String> {
+        let mut busy = HashMap::new();
+        for queue in self.in_flight.values().map(|in_flight| &in_flight.queue) {
+            *busy.entry(queue.clone()).or_default() += 1;
+        }
+        self.queues.candidates(&busy)
+    }
+
+    /// The number of threads in the pool taking new tasks.
+    fn pool_threads(&self) -> usize {
+        self.thread_table.len() - self.retiring_threads.len()
+    }
+
+    /// The current size of the thread pool.
+    fn pool_state(&self) -> PoolState {
+        PoolState {
+            threads: self.pool_threads(),
+            busy_threads: self.in_flight.len(),
+            min_threads: self.min_threads,
+            max_threads: self.max_threads,
+        }
+    }
+
+    /// Grow or shrink the thread pool to `count` threads.
+    /// Idle threads are removed first, and busy threads are removed once they finish their current task.
+    fn resize_pool(&mut self, count: usize) {
+        let mut threads = self.pool_threads();
+        if threads != count {
+            info!(from = threads, to = count; "Resizing thread pool");
+        }
+
+        while threads < count {
+            match self.retiring_threads.iter().next().copied() {
+                Some(thread_id) => {
+                    self.retiring_threads.remove(&thread_id);
+                }
+                None => {
+                    let thread_id = self.next_thread_id;
+                    self.next_thread_id += 1;
+                    self.thread_table.insert(thread_id, spawn_thread(thread_id, &self.messages_tx));
+                }
+            }
+            threads += 1;
+        }
+
+        while threads > count {
+            let thread_id = self.thread_ready_buf
+                .pop()
+                .or_else(|| self.thread_table.keys().find(|id| !self.retiring_threads.contains(id)).copied())
+                .expect("no thread to remove from pool");
+
+            if self.in_flight.values().any(|in_flight| in_flight.thread_id == thread_id) {
+                self.retiring_threads.insert(thread_id);
+            } else {
+                self.remove_thread(thread_id);
+            }
+            threads -= 1;
+        }
+    }
+
+    /// Remove a thread from the pool. The thread exits once it sees it won't be sent any more tasks.
+    fn remove_thread(&mut self, thread_id: ThreadId) {
+        debug!(thread_id; "Removing thread from pool");
+        self.thread_ready_buf.retain(|id| *id != thread_id);
+        self.retiring_threads.remove(&thread_id);
+        self.thread_table.remove(&thread_id);
+    }
+
+    /// Call a method on `Mavrik::ExecuteTask` to raise an error in the Ruby thread processing a task.
+    fn interrupt_thread<A: ArgList>(
+        &self,
+        thread_id: ThreadId,
+        method: &str,
+        args: impl Fn(magnus::Thread) -> A,
+    ) -> Result<(), anyhow::Error> {
+        let entry = self.thread_table.get(&thread_id).expect("thread not found");
+        in_ruby(|_| {
+            module_mavrik()
+                .const_get::<_, RClass>("ExecuteTask")?
+                .funcall::<_, _, magnus::Value>(method, args(entry.thread))
+        })
+        .map_err(|e| anyhow!("signalling thread to interrupt task failed: {e}"))?;
+        Ok(())
+    }
+}
+
+impl<Store> TaskExecutor<Store>
+where
+    Store: ProcessStore<Id = TaskId, Error = anyhow::Error> + QueryStore<Id = TaskId, Error = anyhow::Error>
+{
+    /// Size the thread pool to fit the tasks being processed and waiting in the executor's queues.
+    /// The pool grows straight away to absorb spikes, and shrinks one thread at a time.
+    async fn autoscale(&mut self) {
+        if self.max_threads <= self.min_threads {
+            return;
+        }
+
+        // The pool is left as it is until the next check if the store fails.
+        let waiting = match self.store.enqueued(self.queues.queues()).await {
+            Ok(waiting) => waiting,
+            Err(e) => {
+                warn!(e:?; "Counting enqueued tasks to autoscale thread pool failed");
+                return;
             }
+        };
+
+        let threads = self.pool_threads();
+        let wanted = (self.in_flight.len() + waiting).clamp(self.min_threads, self.max_threads);
+        if wanted > threads {
+            self.resize_pool(wanted);
+        } else if wanted < threads {
+            self.resize_pool(threads - 1);
+        }
+    }
+
+    /// Record the result of a task's attempt, retrying it or moving it to the dead-letter queue if it failed.
+    ///
+    /// If the store fails to record the result, the task's lease runs out and it's processed again.
+    ///
+    async fn complete_task(&mut self, lease_id: LeaseId, task_result: TaskResult) {
+        let Some(InFlightTask { task_id, mut task, .. }) = self.in_flight.remove(&lease_id) else {
+            debug!(lease_id; "Completed task is no longer being processed");
+            return;
+        };
+
+        let cancelled = self.cancelling.remove(&task_id);
+        let recorded = match &task_result {
+            TaskResult::Failure { .. } if cancelled => {
+                info!(task_id; "Task cancelled");
+                self.store.publish_result(task_id, TaskResult::Cancelled).await
+            },
+            TaskResult::Success { .. } | TaskResult::Cancelled => {
+                self.store.publish_result(task_id, task_result).await
+            },
+            TaskResult::Failure { class, message, .. } if task.can_retry(class) => {
+                task.attempts += 1;
+                let delay = self.backoff.delay(task.attempts);
+                info!(task_id, class, message, attempts = task.attempts, delay:?; "Task failed, retrying");
+                self.store.retry(task_id, task, delay).await
+            },
+            TaskResult::Failure { class, message, .. } => {
+                warn!(task_id, class, message; "Task failed, moving to dead-letter queue");
+                self.store.dead_letter(task_id, task_result).await
+            }
+        };
+
+        if let Err(e) = recorded {
+            error!(task_id, e:?; "Recording task result failed, it will be processed again once its lease expires");
+        }
+    }
+
+    /// Replace Ruby threads that have died, failing the tasks they were processing.
+    async fn restart_dead_threads(&mut self) {
+        let dead = in_ruby(|_| {
+            self.thread_table
+                .iter()
+                .filter(|(_, entry)| !entry.thread.funcall_public::<_, _, bool>("alive?", ()).unwrap_or(true))
+                .map(|(thread_id, _)| *thread_id)
+                .collect::<Vec<_>>()
         });
 
-        Ok(Self { store, thread_table, messages_rx, task_buf, thread_ready_buf })
+        for thread_id in dead {
+            if self.retiring_threads.contains(&thread_id) {
+                warn!(thread_id; "Ruby thread died while being removed from pool");
+                self.remove_thread(thread_id);
+            } else {
+                self.thread_restarts += 1;
+                warn!(thread_id, restarts = self.thread_restarts; "Ruby thread died, restarting it");
+                self.thread_ready_buf.retain(|id| *id != thread_id);
+                self.thread_table.insert(thread_id, spawn_thread(thread_id, &self.messages_tx));
+            }
+
+            let orphaned = self.in_flight
+                .iter()
+                .filter(|(_, in_flight)| in_flight.thread_id == thread_id)
+                .map(|(lease_id, _)| *lease_id)
+                .collect::<Vec<_>>();
+            for lease_id in orphaned {
+                let failure = TaskResult::from(anyhow!("thread processing the task died"));
+                self.complete_task(lease_id, failure).await;
+            }
+        }
+    }
+
+    /// Extend the leases of the tasks being processed, so the store doesn't hand them out again while they run.
+    async fn renew_leases(&mut self) {
+        let task_ids = self.in_flight.values().map(|in_flight| in_flight.task_id).collect::<Vec<_>>();
+        if task_ids.is_empty() {
+            return;
+        }
+
+        // The leases are renewed again on the next tick, well before they expire.
+        if let Err(e) = self.store.renew_leases(&task_ids).await {
+            warn!(e:?; "Renewing leases of tasks being processed failed");
+        }
+    }
+}
+
+/// Create a Ruby thread that runs the tasks it's sent.
+fn spawn_thread(thread_id: ThreadId, messages_tx: &mpsc::Sender<ThreadMessage>) -> ThreadTableEntry {
+    let (task_tx, task_rx) = mpsc::channel(1);
+    let mut task_rx = Some(task_rx);
+    let thread = in_ruby(|r| {
+        let messages_tx = messages_tx.clone();
+        let task_rx = task_rx.take().expect("thread already created");
+        r.thread_create_from_fn(move |r| rb_thread_main(r, thread_id, messages_tx, task_rx))
+    });
+
+    ThreadTableEntry { thread, task_tx }
+}
+
+/// Dequeue the next task from one of the given queues.
+///
+/// Store failures are logged and retried after a delay rather than stopping the executor.
+///
+async fn next_task<Store>(store: &Store, queues: &[String]) -> (TaskId, String, Task)
+where
+    Store: ProcessStore<Id = TaskId, Error = anyhow::Error>,
+{
+    loop {
+        match store.dequeue(queues).await {
+            Ok(task) => return task,
+            Err(e) => {
+                warn!(e:?; "Dequeuing next task failed, retrying");
+                sleep(DEQUEUE_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Wait until the given timeout, if any, is reached.
+async fn timed_out(next_timeout: Option<(Instant, LeaseId)>) -> LeaseId {
+    match next_timeout {
+        Some((deadline, lease_id)) => {
+            sleep_until(deadline).await;
+            lease_id
+        }
+        None => pending().await,
     }
 }
 
 impl<Store> ServiceTask for TaskExecutor<Store>
 where
-    Store: ProcessStore<Id = TaskId, Error = anyhow::Error>
+    Store: ProcessStore<Id = TaskId, Error = anyhow::Error> + QueryStore<Id = TaskId, Error = anyhow::Error>
 {
     type ReadyTask = Result<TaskOutputKind, anyhow::Error>;
 
     async fn poll_task(&mut self) -> Self::ReadyTask {
+        let next_timeout = self.next_timeout();
+        let queues = self.next_queues();
+
+        // Only dequeue once a thread can take the task, so it doesn't sit in a buffer while its lease runs out.
         select! { 
-            result = self.store.dequeue(), if self.task_buf.len() < 100 => {
-                Ok(TaskOutputKind::NextTask(result?))
+            task = next_task(&self.store, &queues), if !self.thread_ready_buf.is_empty() && !queues.is_empty() => {
+                Ok(TaskOutputKind::NextTask(task))
             },
             
             Some(message) = self.messages_rx.recv() => {
                 Ok(message.into())
             }
+
+            Some(command) = self.commands_rx.recv() => {
+                Ok(TaskOutputKind::Command(command))
+            }
+
+            lease_id = timed_out(next_timeout) => {
+                Ok(TaskOutputKind::TimeOutTask(lease_id))
+            }
+
+            _ = self.supervise_interval.tick() => {
+                Ok(TaskOutputKind::SuperviseThreads)
+            }
+
+            _ = self.renew_interval.tick() => {
+                Ok(TaskOutputKind::RenewLeases)
+            }
         }
     }
 
     async fn on_task_ready(&mut self, output: Self::ReadyTask) -> Result<(), anyhow::Error> {
         match output? {
-            TaskOutputKind::NextTask((task_id, task)) => {
-                match self.thread_ready_buf.pop() {
-                    Some(thread_id) => {
-                        let entry = self.thread_table.get(&thread_id).expect("thread not found");
-                        entry.task_tx.send((task_id, task)).await?;
-                    },
-                    None => {
-                        self.task_buf.push((task_id, task));
+            TaskOutputKind::NextTask((task_id, queue, task)) => {
+                // A task whose lease expired while it was still running mustn't start again on another thread.
+                if self.in_flight.values().any(|in_flight| in_flight.task_id == task_id) {
+                    warn!(task_id; "Task dequeued again while still being processed, renewing its lease instead");
+                    if let Err(e) = self.store.renew_leases(&[task_id]).await {
+                        warn!(task_id, e:?; "Renewing lease of task being processed failed");
                     }
+                    return Ok(());
+                }
+
+                self.queues.advance(&queue);
+
+                // Tasks over their rate limit wait in their queues until they can start, leaving the thread free.
+                if let Some(wait) = self.rate_limiter.acquire(&task.definition, Instant::now()) {
+                    debug!(task_id, definition = task.definition, wait:?; "Task over its rate limit, holding it back");
+                    if let Err(e) = self.store.postpone(task_id, wait).await {
+                        warn!(task_id, e:?; "Postponing task failed, it will be dequeued again once its lease expires");
+                    }
+                    return Ok(());
+                }
+                let thread_id = self.thread_ready_buf.pop().expect("no thread ready for task");
+                let entry = self.thread_table.get(&thread_id).expect("thread not found");
+                let timeout = task.timeout.map(Duration::from_millis).or(self.default_timeout);
+                let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                let lease_id = self.next_lease_id;
+                self.next_lease_id += 1;
+                let in_flight = InFlightTask { task_id, thread_id, task: task.clone(), queue, deadline };
+                self.in_flight.insert(lease_id, in_flight);
+
+                // The thread only stops receiving once it has died, and the task fails when the thread is restarted.
+                if entry.task_tx.send((lease_id, task_id, task)).await.is_err() {
+                    warn!(task_id, thread_id; "Sending task to thread failed, thread has died");
                 }
                 Ok(())
             }
             
             TaskOutputKind::ThreadReady(thread_id) => {
-                match self.task_buf.pop() {
-                    Some((task_id, task)) => {
-                        let entry = self.thread_table.get(&thread_id).expect("thread not found");
-                        entry.task_tx.send((task_id, task)).await?;
-                    },
-                    None => {
-                        self.thread_ready_buf.push(thread_id);
-                    }
+                // Threads removed from the pool aren't given any more tasks.
+                if self.thread_table.contains_key(&thread_id) {
+                    self.thread_ready_buf.push(thread_id);
                 }
                 Ok(())
             },
             
-            TaskOutputKind::TaskComplete((task_id, task_result)) => {
-                self.store.publish_result(task_id, task_result).await?;
+            TaskOutputKind::Command(ExecutorCommand::CancelTask(task_id)) => {
+                let in_flight = self.in_flight.values().find(|in_flight| in_flight.task_id == task_id);
+                let Some(InFlightTask { thread_id, .. }) = in_flight else {
+                    debug!(task_id; "Task to cancel is no longer being processed");
+                    return Ok(());
+                };
+
+                let thread_id = *thread_id;
+                info!(task_id, thread_id; "Cancelling task being processed");
+                let id = String::from(&task_id);
+                match self.interrupt_thread(thread_id, "cancel", |thread| (thread, id.clone())) {
+                    Ok(()) => {
+                        self.cancelling.insert(task_id);
+                    }
+                    Err(e) => warn!(task_id, thread_id, e:?; "Interrupting cancelled task failed, letting it finish"),
+                }
+                Ok(())
+            }
+
+            TaskOutputKind::Command(ExecutorCommand::GetPoolState { reply }) => {
+                let _ = reply.send(self.pool_state());
+                Ok(())
+            }
+
+            TaskOutputKind::Command(ExecutorCommand::ResizePool { min_threads, max_threads, reply }) => {
+                self.min_threads = min_threads;
+                self.max_threads = max_threads;
+                let threads = self.pool_threads();
+                self.resize_pool(threads.clamp(min_threads, max_threads));
+                let _ = reply.send(self.pool_state());
+                Ok(())
+            }
+
+            TaskOutputKind::TimeOutTask(lease_id) => {
+                let Some(in_flight) = self.in_flight.get_mut(&lease_id) else {
+                    return Ok(());
+                };
+
+                // Only interrupt the thread once; the task fails with the timeout error when the thread gets to it.
+                in_flight.deadline = None;
+                let (task_id, thread_id) = (in_flight.task_id, in_flight.thread_id);
+                let timeout = in_flight.task.timeout
+                    .map(Duration::from_millis)
+                    .or(self.default_timeout)
+                    .unwrap_or_default();
+                warn!(task_id, thread_id, timeout:?; "Task timed out, interrupting it");
+                let id = String::from(&task_id);
+                let interrupted = self.interrupt_thread(thread_id, "time_out", |thread| {
+                    (thread, id.clone(), timeout.as_secs_f64())
+                });
+                if let Err(e) = interrupted {
+                    warn!(task_id, thread_id, e:?; "Interrupting timed out task failed, letting it finish");
+                }
+                Ok(())
+            }
+
+            TaskOutputKind::TaskComplete((lease_id, task_result)) => {
+                // A thread being removed from the pool leaves once it finishes its current task, so the ready message
+                // it sends next is ignored.
+                if let Some(InFlightTask { thread_id, .. }) = self.in_flight.get(&lease_id) {
+                    let thread_id = *thread_id;
+                    if self.retiring_threads.contains(&thread_id) {
+                        self.remove_thread(thread_id);
+                    }
+                }
+                self.complete_task(lease_id, task_result).await;
+                Ok(())
+            }
+
+            TaskOutputKind::SuperviseThreads => {
+                self.restart_dead_threads().await;
+                self.autoscale().await;
+                Ok(())
+            }
+
+            TaskOutputKind::RenewLeases => {
+                self.renew_leases().await;
                 Ok(())
             }
         }
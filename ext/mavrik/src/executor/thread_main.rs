@@ -1,5 +1,5 @@
-use crate::executor::{ThreadId, ThreadMessage};
-use crate::messaging::{Task, TaskId, TaskResult};
+use crate::executor::{LeaseId, ThreadId, ThreadMessage};
+use crate::messaging::{RawBytes, Task, TaskId, TaskResult};
 use crate::rb::util::{mavrik_error, module_mavrik};
 use crate::runtime::async_runtime;
 use crate::{with_gvl, without_gvl};
@@ -7,7 +7,7 @@ use anyhow::anyhow;
 use log::{error, trace};
 use magnus::value::ReprValue;
 use magnus::{Class, Module, RClass, Ruby};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_magnus::{deserialize, serialize};
 use tokio::sync::mpsc;
 
@@ -15,7 +15,7 @@ pub fn rb_thread_main(
     ruby: &Ruby,
     thread_id: ThreadId,
     mut messages_tx: mpsc::Sender<ThreadMessage>,
-    mut task_rx: mpsc::Receiver<(TaskId, Task)>,
+    mut task_rx: mpsc::Receiver<(LeaseId, TaskId, Task)>,
 ) -> Result<(), magnus::Error> {
     let execute_task = module_mavrik()
         .const_get::<_, RClass>("ExecuteTask")?
@@ -38,11 +38,12 @@ pub fn rb_thread_main(
     result.map_err(mavrik_error)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 struct TaskInput<'a> {
+    id: &'a str,
     definition: &'a str,
-    args: &'a str,
-    kwargs: &'a str,
+    args: &'a RawBytes,
+    kwargs: &'a RawBytes,
 }
 
 async fn thread_loop(
@@ -50,50 +51,56 @@ async fn thread_loop(
     thread_id: ThreadId,
     execute_task: magnus::Value,
     messages_tx: &mut mpsc::Sender<ThreadMessage>,
-    task_rx: &mut mpsc::Receiver<(TaskId, Task)>,
+    task_rx: &mut mpsc::Receiver<(LeaseId, TaskId, Task)>,
 ) -> Result<(), anyhow::Error> {
     // Notify executor this thread is ready
     messages_tx
         .send(ThreadMessage::ThreadReady(thread_id))
         .await?;
 
-    while let Some((task_id, task)) = task_rx.recv().await {
+    while let Some((lease_id, task_id, task)) = task_rx.recv().await {
         let Task {
             definition,
             args,
             kwargs,
             ..
         } = &task;
-        trace!(definition, args, kwargs; "Task executing");
+        let id = String::from(&task_id);
+        trace!(definition, args:?, kwargs:?; "Task executing");
 
-        // Any errors raised in the task will be captured in `TaskResult`
-        // We return nested results so we can provide context if things fail.
-        let result: Result<Result<TaskResult, magnus::Error>, magnus::Error> = with_gvl!({
-            let input = serialize(
+        // Any errors raised in the task will be captured in `TaskResult`. Failures around the task, like a result that
+        // can't be converted, fail the task rather than the thread. They're converted while holding the GVL, since the
+        // failure names a Ruby error class.
+        let task_result: TaskResult = with_gvl!({
+            let result = serialize(
                 ruby,
                 &TaskInput {
+                    id: &id,
                     definition,
                     args,
                     kwargs,
                 },
-            )?;
-            trace!(input:?; "Ruby task input");
-
-            execute_task
-                .funcall_public::<_, (magnus::Value,), magnus::Value>("call", (input,))
-                .map(|output| {
-                    trace!(output:?; "Ruby task output");
-                    deserialize(ruby, output)
-                })
+            )
+            .and_then(|input| {
+                trace!(input:?; "Ruby task input");
+                execute_task.funcall_public::<_, (magnus::Value,), magnus::Value>("call", (input,))
+            })
+            .map_err(|e| anyhow!("task execution failed: {e}"))
+            .and_then(|output| {
+                trace!(output:?; "Ruby task output");
+                deserialize(ruby, output).map_err(|e| anyhow!("converting returned hash to task result failed: {e}"))
+            });
+            result.unwrap_or_else(TaskResult::from)
         });
 
-        let task_result = result
-            .map_err(|e| anyhow!("task execution failed: {e}"))?
-            .map_err(|e| anyhow!("converting returned hash to task result failed: {e}"))?;
+        trace!(definition, args:?, kwargs:?; "Task complete");
+        messages_tx
+            .send(ThreadMessage::TaskComplete((lease_id, task_result)))
+            .await?;
 
-        trace!(definition, args, kwargs; "Task complete");
+        // Notify executor this thread is ready for its next task
         messages_tx
-            .send(ThreadMessage::TaskComplete((task_id, task_result)))
+            .send(ThreadMessage::ThreadReady(thread_id))
             .await?;
     }
 
@@ -1,52 +1,164 @@
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{sink, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use log::trace;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::fmt::Debug;
-use std::mem::size_of;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display, Formatter};
 use anyhow::Context;
 
-/// Read and deserialize an object from a stream.
+/// The version of the frame format, sent at the start of every frame.
+pub const FRAME_VERSION: u8 = 1;
+
+/// The largest payload read from a stream unless configured otherwise, 16 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// A frame that breaks the wire format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The frame was written with a version of the frame format this side doesn't speak.
+    ///
+    /// The rest of the stream can't be trusted after this.
+    UnsupportedVersion(u8),
+
+    /// The frame's payload is larger than the maximum frame size.
+    ///
+    /// The payload is skipped, so the next frame can still be read from the stream.
+    FrameTooLarge { len: u32, max: u32 },
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported frame version {version}, expected {FRAME_VERSION}")
+            }
+            ProtocolError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the maximum frame size of {max} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// How the payloads of frames are encoded.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// The encodings this side can read and write frames in, in order of preference.
+pub const ENCODINGS: &[Encoding] = &[Encoding::MessagePack, Encoding::Json];
+
+/// Serialize an object into a payload.
+pub fn encode<T>(encoding: Encoding, object: &T) -> Result<Vec<u8>, anyhow::Error>
+where
+    T: Serialize + ?Sized
+{
+    match encoding {
+        Encoding::Json => serde_json::to_vec(object).context("serializing object to JSON"),
+        Encoding::MessagePack => rmp_serde::to_vec_named(object).context("serializing object to MessagePack"),
+    }
+}
+
+/// Deserialize an object from a payload.
+pub fn decode<T>(encoding: Encoding, payload: &[u8]) -> Result<T, anyhow::Error>
+where
+    T: DeserializeOwned
+{
+    match encoding {
+        Encoding::Json => serde_json::from_slice(payload).context("deserializing JSON payload"),
+        Encoding::MessagePack => rmp_serde::from_slice(payload).context("deserializing MessagePack payload"),
+    }
+}
+
+/// Read a frame from a stream, returning its payload.
 ///
 /// The stream format consists of:
-/// 1. Length header (usize bytes) - Size of the serialized JSON payload
-/// 2. JSON payload (length bytes) - The serialized object
+/// 1. Version header (1 byte) - The version of the frame format, `FRAME_VERSION`
+/// 2. Length header (4 bytes, big-endian) - Size of the payload
+/// 3. Payload (length bytes) - The encoded object
+///
+/// # Arguments
+///
+/// `stream` - The stream to read from.
+/// `max_frame_size` - The largest payload to accept. Larger payloads are skipped and fail with
+///   `ProtocolError::FrameTooLarge`, without allocating space for them.
 ///
-pub async fn read_object<R, T>(stream: &mut R) -> Result<T, anyhow::Error>
+pub async fn read_frame<R>(stream: &mut R, max_frame_size: u32) -> Result<Vec<u8>, anyhow::Error>
 where
-    R: AsyncRead + Unpin,
-    T: DeserializeOwned + Debug
+    R: AsyncRead + Unpin
 {
-    let mut len_buf = [0u8; size_of::<usize>()];
-    stream.read_exact(&mut len_buf).await.context("reading payload length")?;
-    let len = usize::from_be_bytes(len_buf);
+    let version = stream.read_u8().await.context("reading frame version")?;
+    if version != FRAME_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version).into());
+    }
+
+    let len = stream.read_u32().await.context("reading payload length")?;
+    if len > max_frame_size {
+        tokio::io::copy(&mut (&mut *stream).take(len as u64), &mut sink()).await.context("skipping oversized payload")?;
+        return Err(ProtocolError::FrameTooLarge { len, max: max_frame_size }.into());
+    }
 
-    let mut payload = vec![0u8; len];
+    let mut payload = vec![0u8; len as usize];
     stream.read_exact(&mut payload).await.context("reading payload")?;
-    let object = serde_json::from_slice(&payload).context("deserializing JSON payload")?;
+    Ok(payload)
+}
+
+/// Write a frame with the given payload to a stream, in the format read by `read_frame`.
+pub async fn write_frame<W>(stream: &mut W, payload: &[u8]) -> Result<(), anyhow::Error>
+where
+    W: AsyncWrite + Unpin
+{
+    let len = u32::try_from(payload.len()).context("payload too large for a frame")?;
+
+    let mut header = [0u8; 5];
+    header[0] = FRAME_VERSION;
+    header[1..].copy_from_slice(&len.to_be_bytes());
+    stream.write_all(&header).await.context("writing frame header")?;
+    stream.write_all(payload).await.context("writing payload")?;
+    Ok(())
+}
+
+/// Read and deserialize an object from a frame on a stream.
+///
+/// # Arguments
+///
+/// `stream` - The stream to read from.
+/// `encoding` - How the frame's payload is encoded.
+/// `max_frame_size` - The largest payload to accept, see `read_frame`.
+///
+pub async fn read_object<R, T>(stream: &mut R, encoding: Encoding, max_frame_size: u32) -> Result<T, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned + Debug
+{
+    let payload = read_frame(stream, max_frame_size).await?;
+    let object = decode(encoding, &payload)?;
 
-    trace!(len, object:?; "Read object from stream");
+    trace!(len = payload.len(), object:?; "Read object from stream");
     Ok(object)
 }
 
-/// Write and serialize an object to a stream.
+/// Serialize and write an object as a frame to a stream.
 ///
-/// The stream format consists of:
-/// 1. Length header (usize bytes) - Size of the serialized JSON payload
-/// 2. JSON payload (length bytes) - The serialized object
+/// # Arguments
 ///
-pub async fn write_object<W, T>(stream: &mut W, object: T) -> Result<(), anyhow::Error>
+/// `stream` - The stream to write to.
+/// `encoding` - How to encode the frame's payload.
+/// `object` - The object to write.
+///
+pub async fn write_object<W, T>(stream: &mut W, encoding: Encoding, object: T) -> Result<(), anyhow::Error>
 where
     W: AsyncWrite + Unpin,
     T: Serialize + Debug
 {
-    let payload = serde_json::to_string(&object).context("serializing object to JSON")?;
-    let len = payload.len();
-    
-    stream.write(&len.to_be_bytes()).await.context("writing payload length")?;
-    stream.write_all(payload.as_bytes()).await.context("writing payload")?;
+    let payload = encode(encoding, &object)?;
+    write_frame(stream, &payload).await?;
 
-    trace!(len, object:?; "Wrote object to stream");
+    trace!(len = payload.len(), object:?; "Wrote object to stream");
     Ok(())
 }
 
@@ -70,9 +182,9 @@ mod tests {
         };
 
         let mut buffer = Cursor::new(Vec::new());
-        write_object(&mut buffer, &test_obj).await.unwrap();
+        write_object(&mut buffer, Encoding::Json, &test_obj).await.unwrap();
         buffer.set_position(0);
-        let read_obj: TestObject = read_object(&mut buffer).await.unwrap();
+        let read_obj: TestObject = read_object(&mut buffer, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
         
         assert_eq!(test_obj, read_obj);
     }
@@ -82,12 +194,59 @@ mod tests {
         let obj = None;
 
         let mut buffer = Cursor::new(Vec::new());
-        write_object(&mut buffer, &obj).await.unwrap();
+        write_object(&mut buffer, Encoding::Json, &obj).await.unwrap();
         buffer.set_position(0);
-        let read_obj: Option<TestObject> = read_object(&mut buffer).await.unwrap();
+        let read_obj: Option<TestObject> =
+            read_object(&mut buffer, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
 
         assert_eq!(obj, read_obj);
     }
-}
 
+    #[tokio::test]
+    async fn test_roundtrip_message_pack() {
+        let test_obj = TestObject {
+            field1: "test".to_string(),
+            field2: 42,
+        };
 
+        let mut buffer = Cursor::new(Vec::new());
+        write_object(&mut buffer, Encoding::MessagePack, &test_obj).await.unwrap();
+        buffer.set_position(0);
+        let read_obj: TestObject =
+            read_object(&mut buffer, Encoding::MessagePack, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+
+        assert_eq!(test_obj, read_obj);
+    }
+
+    #[tokio::test]
+    async fn test_frame_header_is_version_and_u32_length() {
+        let mut buffer = Cursor::new(Vec::new());
+        write_object(&mut buffer, Encoding::Json, 42).await.unwrap();
+
+        assert_eq!(buffer.into_inner(), [FRAME_VERSION, 0, 0, 0, 2, b'4', b'2']);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_skipped() {
+        let big_obj = TestObject { field1: "x".repeat(100), field2: 1 };
+        let small_obj = TestObject { field1: "test".to_string(), field2: 2 };
+
+        let mut buffer = Cursor::new(Vec::new());
+        write_object(&mut buffer, Encoding::Json, &big_obj).await.unwrap();
+        write_object(&mut buffer, Encoding::Json, &small_obj).await.unwrap();
+        buffer.set_position(0);
+
+        let err = read_object::<_, TestObject>(&mut buffer, Encoding::Json, 64).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ProtocolError::FrameTooLarge { max: 64, .. })));
+        let read_obj: TestObject = read_object(&mut buffer, Encoding::Json, 64).await.unwrap();
+        assert_eq!(small_obj, read_obj);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version_is_rejected() {
+        let mut buffer = Cursor::new(vec![FRAME_VERSION + 1, 0, 0, 0, 2, b'4', b'2']);
+
+        let err = read_object::<_, i32>(&mut buffer, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ProtocolError::UnsupportedVersion(FRAME_VERSION + 1)));
+    }
+}
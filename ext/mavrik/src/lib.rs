@@ -9,6 +9,7 @@ pub mod runtime;
 pub mod mavrik;
 pub mod service;
 pub mod executor;
+pub mod scheduler;
 pub mod store;
 
 #[magnus::init]
@@ -46,6 +47,7 @@ mod tests {
             util::tests::mavrik_module_is_defined(&ruby)?;
             util::tests::mavrik_error_class_is_defined(&ruby)?;
             util::tests::mavrik_error_uses_custom_message(&ruby)?;
+            util::tests::server_error_uses_class_for_code(&ruby)?;
             util::tests::in_ruby_calls_fn_in_gvl(&ruby)?;
             util::tests::in_ruby_locks_gvl_then_calls_fn(&ruby)?;
             
@@ -57,6 +59,11 @@ mod tests {
         r.eval(r#"
           module Mavrik
             Error = Class.new(StandardError)
+            InvalidRequest = Class.new(Error)
+            NotFound = Class.new(Error)
+            StoreUnavailable = Class.new(Error)
+            Overloaded = Class.new(Error)
+            ServerError = Class.new(Error)
           end
         "#)
     }
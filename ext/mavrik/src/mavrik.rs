@@ -1,11 +1,20 @@
-use crate::executor::TaskExecutor;
+use crate::executor::{RateLimit, TaskExecutor};
+use crate::messaging::{RecurringSchedule, TaskId};
+use crate::scheduler::Scheduler;
 use crate::service::Services;
 use crate::signal_listener::SignalListener;
-use crate::store::TasksInMemory;
+use crate::store::{
+    ProcessStore, PullStore, PushStore, QueryStore, QueueOrder, ScheduleStore, TasksInFile, TasksInMemory,
+    TasksInSqlite, DEFAULT_LEASE_TIMEOUT,
+};
 use crate::tcp::MavrikTcpListener;
 use log::info;
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_blocking;
 use tokio::try_join;
 
 pub struct Mavrik<'a> {
@@ -18,32 +27,77 @@ impl<'a> Mavrik<'a> {
     }
 
     pub async fn run(self) -> Result<(), anyhow::Error> {
+        let order = self.options.queue_order.unwrap_or_default();
+        let lease_timeout = self.options.lease_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_LEASE_TIMEOUT);
+        let store_options = self.options.store.clone().unwrap_or_default();
+        info!(store_options:?; "Opening task store");
+
+        match store_options {
+            StoreOptions::Memory => {
+                let store = TasksInMemory::with_order(order).with_lease_timeout(lease_timeout);
+                self.run_with_store(store).await
+            }
+
+            StoreOptions::File { path, retention } => {
+                let retention = retention.map(Duration::from_secs);
+                let store = spawn_blocking(move || TasksInFile::open(path, order, retention)).await??;
+                let store = store.with_lease_timeout(lease_timeout);
+                self.run_with_store(store).await
+            }
+
+            StoreOptions::Sqlite { path, retention } => {
+                let retention = retention.map(Duration::from_secs);
+                let store = spawn_blocking(move || TasksInSqlite::open(path, order, retention)).await??;
+                let store = store.with_lease_timeout(lease_timeout);
+                self.run_with_store(store).await
+            }
+        }
+    }
+
+    async fn run_with_store<Store>(self, store: Store) -> Result<(), anyhow::Error>
+    where
+        Store: ProcessStore<Id = TaskId, Error = anyhow::Error>
+            + PushStore<Id = TaskId, Error = anyhow::Error>
+            + PullStore<Id = TaskId, Error = anyhow::Error>
+            + QueryStore<Id = TaskId, Error = anyhow::Error>
+            + ScheduleStore<Id = TaskId, Error = anyhow::Error>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
         let (term_tx, term_rx) = oneshot::channel();
-        let task_memory = TasksInMemory::new();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
 
         let mut exe = Services::start(
             "EXE",
-            TaskExecutor::new(&self.options, task_memory.clone())?,
+            TaskExecutor::new(self.options, store.clone(), commands_rx)?,
+        );
+        let mut sch = Services::start(
+            "SCH",
+            Scheduler::new(self.options, store.clone()).await?,
         );
         let mut tcp = Services::start(
             "TCP",
-            MavrikTcpListener::new(&self.options, task_memory).await?,
+            MavrikTcpListener::new(self.options, store, commands_tx).await?,
         );
         let mut sig = Services::start("SIG", SignalListener::new(term_tx)?);
 
         let exe_chan = &mut exe.channel;
+        let sch_chan = &mut sch.channel;
         let tcp_chan = &mut tcp.channel;
         let sig_chan = &mut sig.channel;
         let cleanup_task = Box::pin(async move {
             let _ = term_rx.await?;
             info!("Received request for termination");
             exe_chan.terminate();
+            sch_chan.terminate();
             tcp_chan.terminate();
             sig_chan.terminate();
             Ok(())
         });
 
-        try_join!(exe.task, tcp.task, sig.task, cleanup_task)?;
+        try_join!(exe.task, sch.task, tcp.task, sig.task, cleanup_task)?;
         info!("Mavrik stopped");
         Ok(())
     }
@@ -53,6 +107,46 @@ impl<'a> Mavrik<'a> {
 pub struct MavrikOptions {
     pub host: Option<String>,
     pub port: Option<u16>,
+    pub max_frame_size: Option<u32>,
     pub rb_thread_count: Option<usize>,
+    pub rb_thread_max_count: Option<usize>,
+    pub queues: Option<Vec<String>>,
+    pub queue_order: Option<QueueOrder>,
+    pub queue_weights: Option<HashMap<String, u32>>,
+    pub queue_concurrency: Option<HashMap<String, usize>>,
+    pub store: Option<StoreOptions>,
+    pub lease_timeout: Option<u64>,
+    pub retry_base_delay: Option<u64>,
+    pub retry_max_delay: Option<u64>,
+    pub task_timeout: Option<u64>,
+    pub rate_limits: Option<HashMap<String, RateLimit>>,
+    pub schedules: Option<Vec<RecurringSchedule>>,
     pub signal_parent_ready: Option<bool>,
 }
+
+/// Options for choosing and configuring the backend tasks are stored in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StoreOptions {
+    /// Keep tasks in memory. Tasks are lost when the server stops.
+    #[default]
+    Memory,
+
+    /// Keep tasks in memory, backed by a write-ahead log file that's replayed on startup.
+    File {
+        /// The path of the log file.
+        path: PathBuf,
+
+        /// How long, in seconds, to keep results that haven't been pulled. Results are kept forever if not set.
+        retention: Option<u64>,
+    },
+
+    /// Keep tasks in an embedded SQLite database.
+    Sqlite {
+        /// The path of the database file.
+        path: PathBuf,
+
+        /// How long, in seconds, to keep results that haven't been pulled. Results are kept forever if not set.
+        retention: Option<u64>,
+    },
+}
@@ -0,0 +1,118 @@
+use crate::io::{Encoding, ENCODINGS};
+use serde::{Deserialize, Serialize};
+
+/// The version of the requests and responses exchanged after the handshake, bumped for changes older peers can't
+/// understand.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The version of Mavrik, advertised so incompatible peers can be told apart in logs.
+pub const MAVRIK_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The first message a client sends on a connection, before any request.
+///
+/// Handshakes are always sent as JSON, and their shape must never change, so peers of any version can read them and
+/// fail clearly.
+///
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Hello {
+    /// The version of the protocol the client speaks.
+    pub protocol_version: u32,
+
+    /// The version of Mavrik the client is running.
+    pub mavrik_version: String,
+
+    /// The encodings the client supports, in order of preference.
+    pub encodings: Vec<Encoding>,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            mavrik_version: MAVRIK_VERSION.to_string(),
+            encodings: ENCODINGS.to_vec(),
+        }
+    }
+}
+
+/// The server's answer to a client's `Hello`, after which frames are sent in the chosen encoding.
+///
+/// Handshakes are always sent as JSON, and their shape must never change, so peers of any version can read them and
+/// fail clearly.
+///
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Welcome {
+    /// The version of the protocol the server speaks.
+    pub protocol_version: u32,
+
+    /// The version of Mavrik the server is running.
+    pub mavrik_version: String,
+
+    /// The encodings the server supports, in order of preference.
+    pub encodings: Vec<Encoding>,
+
+    /// The encoding frames are sent in for the rest of the session, the first of the client's encodings the server
+    /// supports, or nothing if they have none in common.
+    pub encoding: Option<Encoding>,
+
+    /// What the server can do for the client.
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// The encoding of the session, the first of the client's encodings this side supports, if any.
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encodings.iter().find(|encoding| ENCODINGS.contains(encoding)).copied()
+    }
+}
+
+impl Welcome {
+    /// Create the server's answer to a client's `Hello`.
+    pub fn new(hello: &Hello, capabilities: Capabilities) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            mavrik_version: MAVRIK_VERSION.to_string(),
+            encodings: ENCODINGS.to_vec(),
+            encoding: hello.encoding(),
+            capabilities,
+        }
+    }
+}
+
+/// Limits and features of the server, advertised during the handshake.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Capabilities {
+    /// The largest request payload the server accepts, in bytes.
+    pub max_frame_size: u32,
+
+    /// The most requests the server processes at once on a connection before rejecting more as overloaded.
+    pub max_concurrent_requests: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn welcome_chooses_the_first_common_encoding() {
+        let capabilities = Capabilities { max_frame_size: 1024, max_concurrent_requests: 8 };
+        let welcome = Welcome::new(&Hello::default(), capabilities.clone());
+        assert_eq!(welcome.encoding, Some(Encoding::MessagePack));
+
+        let hello = Hello { encodings: vec![Encoding::Json], ..Hello::default() };
+        assert_eq!(Welcome::new(&hello, capabilities.clone()).encoding, Some(Encoding::Json));
+
+        let hello = Hello { encodings: vec![], ..Hello::default() };
+        assert_eq!(Welcome::new(&hello, capabilities).encoding, None);
+    }
+
+    #[test]
+    fn hello_keeps_its_wire_shape() -> Result<(), anyhow::Error> {
+        let hello = Hello { protocol_version: 1, mavrik_version: "0.1.0".to_string(), encodings: vec![Encoding::Json] };
+        assert_eq!(
+            serde_json::to_string(&hello)?,
+            r#"{"protocol_version":1,"mavrik_version":"0.1.0","encodings":["json"]}"#
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,23 @@
+use crate::executor::PoolState;
 use crate::messaging::task_id::TaskId;
-use crate::messaging::NewTask;
-use crate::store::StoreState;
+use crate::messaging::{NewTask, RecurringSchedule, TaskResult};
+use crate::store::{DeadTask, StoreState, StoredSchedule, StoredTask};
 use serde::{Deserialize, Serialize};
 
+/// A message sent over a TCP connection, tagged with the ID that correlates a request with its response.
+///
+/// Clients give each request on a connection its own ID, and the server answers with the same ID, so many requests can
+/// be in flight on one connection and answered in any order.
+///
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Envelope<T> {
+    /// The ID of the request, or of the request being responded to.
+    pub correlation_id: u64,
+
+    /// The request or response.
+    pub message: T,
+}
+
 /// A request made from a TCP client to the TCP listener service ("TCP").
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -10,18 +25,217 @@ pub enum MavrikRequest {
     /// A new task being submitted.
     NewTask { queue: String, payload: NewTask },
 
+    /// Wait for the result of a task, for up to `timeout` milliseconds if given.
+    AwaitResult { task_id: TaskId, timeout: Option<u64> },
+
+    /// Look up a task without waiting for it to finish or taking its result.
+    GetTask { task_id: TaskId },
+
+    /// Cancel a task, removing it from its queue or stopping it if it's being processed.
+    CancelTask { task_id: TaskId },
+
     /// Get the state of the storage container.
     GetStoreState,
+
+    /// List the tasks in the dead-letter queue.
+    ListDeadTasks,
+
+    /// Return tasks in the dead-letter queue to their queues, or all of them if no IDs are given.
+    RequeueDeadTasks { task_ids: Option<Vec<TaskId>> },
+
+    /// Remove tasks from the dead-letter queue, or all of them if no IDs are given.
+    PurgeDeadTasks { task_ids: Option<Vec<TaskId>> },
+
+    /// List the recurring schedules.
+    ListSchedules,
+
+    /// Add a recurring schedule, or replace the schedule with the same name.
+    AddSchedule { schedule: RecurringSchedule },
+
+    /// Remove a recurring schedule by name.
+    RemoveSchedule { name: String },
+
+    /// Get the size of the executor's Ruby thread pool.
+    GetPoolState,
+
+    /// Resize the executor's Ruby thread pool, autoscaling between the sizes if `max_threads` is larger.
+    ResizePool { min_threads: usize, max_threads: Option<usize> },
 }
 
 /// A response given to a TCP client from the TCP listener service ("TCP").
+///
+/// Responses are tagged with their variant, so responses whose contents look alike, such as `TimedOut` and
+/// `Task(None)`, can still be told apart.
+///
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged, rename_all = "snake_case")]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
 pub enum MavrikResponse {
     /// The response for submitting a new task.
     /// Contains the created ID of the task submitted.
     NewTaskId(TaskId),
 
+    /// The result of a task that was waited for.
+    TaskResult(TaskResult),
+
+    /// The task didn't finish before the request timed out.
+    TimedOut,
+
+    /// The task that was looked up, or nothing if it isn't stored.
+    Task(Option<StoredTask>),
+
     /// The state of the storage container.
     StoreState(StoreState),
+
+    /// The tasks in the dead-letter queue, along with the results of their final attempts.
+    DeadTasks(Vec<DeadTask>),
+
+    /// The recurring schedules, along with when they last fired.
+    Schedules(Vec<StoredSchedule>),
+
+    /// A recurring schedule that was added or replaced.
+    Schedule(StoredSchedule),
+
+    /// The number of tasks affected by the request.
+    Count(usize),
+
+    /// What cancelling a task did.
+    Cancellation(CancelState),
+
+    /// The size of the executor's Ruby thread pool.
+    PoolState(PoolState),
+
+    /// The request failed.
+    Error { code: ErrorCode, message: String },
+}
+
+/// What cancelling a task did.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelState {
+    /// The task hadn't started, so it was removed from its queue.
+    Cancelled,
+
+    /// The task is running, and the executor was asked to interrupt it. Its result shows whether it stopped in time.
+    Cancelling,
+
+    /// The task had already finished, or isn't stored.
+    NotFound,
+}
+
+/// Why a request failed, so clients can handle failures without parsing messages.
+///
+/// These are part of the wire format, so existing codes must not be renamed.
+///
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was malformed or its arguments were invalid.
+    InvalidRequest,
+
+    /// The request refers to something the server doesn't have, such as an unknown task.
+    NotFound,
+
+    /// The store failed to serve the request.
+    StoreUnavailable,
+
+    /// The connection has too many requests in progress to take another.
+    Overloaded,
+
+    /// The request failed for any other reason.
+    Internal,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::PoolState;
+    use crate::messaging::{Envelope, ErrorCode, MavrikRequest, MavrikResponse, TaskId, TaskResult};
+
+    #[test]
+    fn await_result_request_deserializes_with_optional_timeout() -> Result<(), anyhow::Error> {
+        let request = serde_json::from_str::<MavrikRequest>(r#"{"type":"await_result","task_id":"0-123-4"}"#)?;
+        assert_eq!(request, MavrikRequest::AwaitResult { task_id: TaskId::from_parts(123, 4), timeout: None });
+        Ok(())
+    }
+
+    #[test]
+    fn enveloped_requests_carry_their_correlation_id() -> Result<(), anyhow::Error> {
+        let request = serde_json::from_str::<Envelope<MavrikRequest>>(
+            r#"{"correlation_id":7,"message":{"type":"get_store_state"}}"#
+        )?;
+        assert_eq!(request, Envelope { correlation_id: 7, message: MavrikRequest::GetStoreState });
+
+        let response = serde_json::to_string(&Envelope { correlation_id: 7, message: MavrikResponse::TimedOut })?;
+        assert_eq!(response, r#"{"correlation_id":7,"message":{"type":"timed_out"}}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn resize_pool_request_deserializes_with_optional_max() -> Result<(), anyhow::Error> {
+        let request = serde_json::from_str::<MavrikRequest>(r#"{"type":"resize_pool","min_threads":2}"#)?;
+        assert_eq!(request, MavrikRequest::ResizePool { min_threads: 2, max_threads: None });
+        Ok(())
+    }
+
+    #[test]
+    fn pool_state_responses_round_trip() -> Result<(), anyhow::Error> {
+        let pool = PoolState { threads: 4, busy_threads: 1, min_threads: 2, max_threads: 8 };
+        let response = serde_json::to_string(&MavrikResponse::PoolState(pool.clone()))?;
+        match serde_json::from_str::<MavrikResponse>(&response)? {
+            MavrikResponse::PoolState(received) => assert_eq!(received, pool),
+            other => panic!("unexpected response {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn awaited_result_responses_round_trip() -> Result<(), anyhow::Error> {
+        let result = TaskResult::Success { result: "6".to_string() };
+        let response = serde_json::to_string(&MavrikResponse::TaskResult(result.clone()))?;
+        match serde_json::from_str::<MavrikResponse>(&response)? {
+            MavrikResponse::TaskResult(received) => assert_eq!(received, result),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        let response = serde_json::to_string(&MavrikResponse::TimedOut)?;
+        assert_eq!(response, r#"{"type":"timed_out"}"#);
+        assert!(matches!(serde_json::from_str::<MavrikResponse>(&response)?, MavrikResponse::TimedOut));
+        Ok(())
+    }
+
+    #[test]
+    fn empty_responses_round_trip_to_their_own_variants() -> Result<(), anyhow::Error> {
+        let response = serde_json::to_string(&MavrikResponse::Task(None))?;
+        assert_eq!(response, r#"{"type":"task","value":null}"#);
+        assert!(matches!(serde_json::from_str::<MavrikResponse>(&response)?, MavrikResponse::Task(None)));
+
+        let response = serde_json::to_string(&MavrikResponse::Schedules(Vec::new()))?;
+        assert_eq!(response, r#"{"type":"schedules","value":[]}"#);
+        match serde_json::from_str::<MavrikResponse>(&response)? {
+            MavrikResponse::Schedules(schedules) => assert!(schedules.is_empty()),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        let response = serde_json::to_string(&MavrikResponse::DeadTasks(Vec::new()))?;
+        assert_eq!(response, r#"{"type":"dead_tasks","value":[]}"#);
+        match serde_json::from_str::<MavrikResponse>(&response)? {
+            MavrikResponse::DeadTasks(dead_tasks) => assert!(dead_tasks.is_empty()),
+            other => panic!("unexpected response {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn error_responses_round_trip() -> Result<(), anyhow::Error> {
+        let response = MavrikResponse::Error { code: ErrorCode::NotFound, message: "task 0-1-0 not found".to_string() };
+        let response = serde_json::to_string(&response)?;
+        assert_eq!(response, r#"{"type":"error","value":{"code":"not_found","message":"task 0-1-0 not found"}}"#);
+        match serde_json::from_str::<MavrikResponse>(&response)? {
+            MavrikResponse::Error { code, message } => {
+                assert_eq!(code, ErrorCode::NotFound);
+                assert_eq!(message, "task 0-1-0 not found");
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+        Ok(())
+    }
 }
@@ -1,7 +1,13 @@
+mod handshake;
 mod messages;
+mod raw_bytes;
+mod schedule;
 mod task;
 mod task_id;
 
+pub use handshake::*;
 pub use messages::*;
+pub use raw_bytes::*;
+pub use schedule::*;
 pub use task::*;
-pub use task_id::*;
\ No newline at end of file
+pub use task_id::*;
@@ -0,0 +1,140 @@
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+
+/// Already serialized data, such as the arguments of a task, kept as raw bytes.
+///
+/// Binary encodings carry the bytes as they are, rather than escaping them into a string. Human-readable encodings
+/// like JSON carry them as a string, so the bytes must be UTF-8 to be sent that way.
+///
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct RawBytes(Vec<u8>);
+
+impl RawBytes {
+    /// The bytes as a string, if they're UTF-8.
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.0)
+    }
+}
+
+impl Deref for RawBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for RawBytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<RawBytes> for Vec<u8> {
+    fn from(value: RawBytes) -> Self {
+        value.0
+    }
+}
+
+impl From<String> for RawBytes {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes())
+    }
+}
+
+impl From<&str> for RawBytes {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl Debug for RawBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Serialize for RawBytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer
+    {
+        if serializer.is_human_readable() {
+            let string = self.as_str().map_err(serde::ser::Error::custom)?;
+            serializer.serialize_str(string)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RawBytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(RawBytesVisitor)
+    }
+}
+
+struct RawBytesVisitor;
+
+impl<'de> Visitor<'de> for RawBytesVisitor {
+    type Value = RawBytes;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str("a string or bytes")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(RawBytes::from(v))
+    }
+
+    fn visit_string<E: Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(RawBytes::from(v))
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(RawBytes::from(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(RawBytes::from(v))
+    }
+
+    // Some encodings can only send bytes as a sequence of integers.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(RawBytes::from(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_bytes_are_a_string_in_json() -> Result<(), anyhow::Error> {
+        let args = RawBytes::from(r#"[1,"two"]"#);
+        let json = serde_json::to_string(&args)?;
+        assert_eq!(json, r#""[1,\"two\"]""#);
+        assert_eq!(serde_json::from_str::<RawBytes>(&json)?, args);
+        Ok(())
+    }
+
+    #[test]
+    fn raw_bytes_are_bytes_in_message_pack() -> Result<(), anyhow::Error> {
+        let args = RawBytes::from(r#"[1,"two"]"#);
+        let packed = rmp_serde::to_vec(&args)?;
+        // A bin 8 header, the length, then the bytes as they are.
+        assert_eq!(packed[..2], [0xc4, 9]);
+        assert_eq!(&packed[2..], &*args);
+        assert_eq!(rmp_serde::from_slice::<RawBytes>(&packed)?, args);
+        Ok(())
+    }
+}
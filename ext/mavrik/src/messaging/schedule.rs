@@ -0,0 +1,35 @@
+use crate::messaging::Task;
+use serde::{Deserialize, Serialize};
+
+/// A task that's submitted over and over on a cron schedule.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct RecurringSchedule {
+    pub name: String,
+    pub cron: String, // Evaluated in UTC
+    #[serde(default = "default_queue")]
+    pub queue: String,
+    pub definition: String,
+    pub args: String, // Serialized
+    pub kwargs: String // Serialized
+}
+
+fn default_queue() -> String {
+    "default".to_string()
+}
+
+impl From<&RecurringSchedule> for Task {
+    fn from(value: &RecurringSchedule) -> Self {
+        Self {
+            definition: value.definition.clone(),
+            args: value.args.clone().into(),
+            kwargs: value.kwargs.clone().into(),
+            priority: 0,
+            attempts: 0,
+            max_attempts: None,
+            non_retryable: vec![],
+            timeout: None,
+            concurrency_key: None,
+            concurrency_limit: None,
+        }
+    }
+}
@@ -1,18 +1,68 @@
+use crate::messaging::RawBytes;
 use crate::rb::util::class_mavrik_error;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct NewTask {
     pub definition: String,
-    pub args: String, // Serialized
-    pub kwargs: String // Serialized
+    pub args: RawBytes, // Serialized
+    pub kwargs: RawBytes, // Serialized
+    #[serde(default)]
+    pub priority: i32, // Higher priorities are dequeued first
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    #[serde(default)]
+    pub non_retryable: Vec<String>, // Error classes that fail the task without retrying
+    #[serde(default)]
+    pub timeout: Option<u64>, // Milliseconds each attempt can run for
+    #[serde(default)]
+    pub concurrency_key: Option<String>, // Tasks sharing a key are limited in how many run at once
+    #[serde(default)]
+    pub concurrency_limit: Option<u32>, // Tasks with the key that can run at once, 1 by default
+    #[serde(default)]
+    pub run_at: Option<u64>, // Milliseconds since the Unix epoch
+    #[serde(default)]
+    pub delay: Option<u64> // Milliseconds
+}
+
+impl NewTask {
+    /// When the task should be run, if it's scheduled for later rather than run as soon as possible.
+    pub fn due_at(&self) -> Option<SystemTime> {
+        match (self.run_at, self.delay) {
+            (Some(run_at), _) => Some(SystemTime::UNIX_EPOCH + Duration::from_millis(run_at)),
+            (None, Some(delay)) => Some(SystemTime::now() + Duration::from_millis(delay)),
+            (None, None) => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Task {
     pub definition: String,
-    pub args: String, // Serialized
-    pub kwargs: String // Serialized
+    pub args: RawBytes, // Serialized
+    pub kwargs: RawBytes, // Serialized
+    #[serde(default)]
+    pub priority: i32, // Higher priorities are dequeued first
+    #[serde(default)]
+    pub attempts: u32, // Failed attempts so far
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    #[serde(default)]
+    pub non_retryable: Vec<String>, // Error classes that fail the task without retrying
+    #[serde(default)]
+    pub timeout: Option<u64>, // Milliseconds each attempt can run for
+    #[serde(default)]
+    pub concurrency_key: Option<String>, // Tasks sharing a key are limited in how many run at once
+    #[serde(default)]
+    pub concurrency_limit: Option<u32> // Tasks with the key that can run at once, 1 by default
+}
+
+impl Task {
+    /// Whether the task can be attempted again after its latest attempt failed with an error of class `class`.
+    pub fn can_retry(&self, class: &str) -> bool {
+        self.attempts + 1 < self.max_attempts.unwrap_or(1) && !self.non_retryable.iter().any(|c| c == class)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -25,7 +75,8 @@ pub enum TaskResult {
         class: String,
         message: String,
         backtrace: Vec<String>
-    }
+    },
+    Cancelled
 }
 
 impl From<NewTask> for Task {
@@ -34,10 +85,45 @@ impl From<NewTask> for Task {
             definition: value.definition,
             args: value.args,
             kwargs: value.kwargs,
+            priority: value.priority,
+            attempts: 0,
+            max_attempts: value.max_attempts,
+            non_retryable: value.non_retryable,
+            timeout: value.timeout,
+            concurrency_key: value.concurrency_key,
+            concurrency_limit: value.concurrency_limit,
         }
     }
 }
 
+#[cfg(test)]
+impl NewTask {
+    /// A new task with the given definition and no arguments or options, for tests.
+    pub fn for_test(definition: &str) -> Self {
+        Self {
+            definition: definition.to_string(),
+            args: "[]".into(),
+            kwargs: "{}".into(),
+            priority: 0,
+            max_attempts: None,
+            non_retryable: vec![],
+            timeout: None,
+            concurrency_key: None,
+            concurrency_limit: None,
+            run_at: None,
+            delay: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Task {
+    /// A task with the given definition and no arguments or options, for tests.
+    pub fn for_test(definition: &str) -> Self {
+        Task::from(NewTask::for_test(definition))
+    }
+}
+
 impl From<anyhow::Error> for TaskResult {
     fn from(value: anyhow::Error) -> Self {
         TaskResult::Failure {
@@ -3,11 +3,51 @@ use log::kv::{ToValue, Value};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{Debug, Display, Formatter};
+use std::ops::DerefMut;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 #[derive(Copy, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct TaskId([u8; 20]);
 
 impl TaskId {
+    /// Generate a new, unique task ID.
+    ///
+    /// IDs are ordered by the time they were generated.
+    ///
+    pub fn generate() -> Self {
+        // (timestamp, counter)
+        static LAST: Mutex<(u128, u32)> = Mutex::new((0, 0));
+
+        let mut guard = LAST.lock().unwrap();
+        let (last_timestamp, last_count) = guard.deref_mut();
+
+        // Use system timestamp as primary identifier
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        // Append counter to end in case of conflict with timestamp.
+        if *last_timestamp == timestamp {
+            *last_count += 1;
+        } else {
+            *last_timestamp = timestamp;
+            *last_count = 0;
+        };
+        let n = *last_count;
+
+        Self::from_parts(timestamp, n)
+    }
+
+    /// When the ID was generated.
+    pub fn created_at(&self) -> SystemTime {
+        let mut timestamp_buf = [0u8; 16];
+        timestamp_buf.clone_from_slice(&self.0[..16]);
+        let timestamp = u128::from_be_bytes(timestamp_buf);
+        SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp as u64)
+    }
+
     pub fn from_parts(timestamp: u128, count: u32) -> Self {
         let mut buf = [0u8; 20];
         (&mut buf[..16]).clone_from_slice(&timestamp.to_be_bytes());
@@ -111,6 +151,20 @@ mod tests {
     use anyhow::anyhow;
     use crate::messaging::task_id::TaskId;
 
+    #[test]
+    fn task_id_generates_increasing_ids() {
+        let first = TaskId::generate();
+        let second = TaskId::generate();
+        assert!(first < second);
+    }
+
+    #[test]
+    fn task_id_knows_when_it_was_created() {
+        let task_id = TaskId::from_parts(1729971388959, 3);
+        let created_at = task_id.created_at().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(created_at.as_millis(), 1729971388959);
+    }
+
     #[test]
     fn task_id_displays() {
         let task_id = TaskId::from_parts(1729971388959, 3);
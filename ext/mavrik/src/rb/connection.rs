@@ -1,10 +1,12 @@
+use crate::io::DEFAULT_MAX_FRAME_SIZE;
 use crate::messaging::{MavrikRequest, MavrikResponse};
-use crate::rb::util::{mavrik_error, module_mavrik};
+use crate::rb::util::{mavrik_error, module_mavrik, server_error};
 use crate::runtime::async_runtime;
 use crate::tcp::{MavrikTcpClient, TcpClientOptions};
 use crate::{ruby_or_mavrik_error, without_gvl};
 use anyhow::Context;
 use log::debug;
+use magnus::value::ReprValue;
 use magnus::{function, method, Module, Object, Ruby};
 use serde::{Deserialize, Serialize};
 use serde_magnus::{deserialize, serialize};
@@ -26,6 +28,7 @@ pub struct RbConnection {
 pub struct RbConnectionConfig {
     pub host: Option<String>,
     pub port: Option<u16>,
+    pub max_frame_size: Option<u32>,
 }
 
 impl RbConnection {
@@ -36,7 +39,8 @@ impl RbConnection {
 
         let host = config.host.unwrap_or("127.0.0.1".to_owned());
         let port = config.port.unwrap_or(3001);
-        let options = TcpClientOptions { host, port };
+        let max_frame_size = config.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        let options = TcpClientOptions { host, port, max_frame_size };
 
         let tcp_client = async_runtime()
             .block_on(async move { MavrikTcpClient::new(options).await })
@@ -49,30 +53,37 @@ impl RbConnection {
         let ruby = ruby_or_mavrik_error!()?;
         let req = deserialize(&ruby, req)?;
         let res = without_gvl!({ self.send(&req).map_err(mavrik_error) })?;
-        serialize(&ruby, &res)
+        match res {
+            MavrikResponse::NewTaskId(task_id) => serialize(&ruby, &task_id),
+            MavrikResponse::TaskResult(result) => serialize(&ruby, &result),
+            MavrikResponse::TimedOut => Ok(ruby.qnil().as_value()),
+            MavrikResponse::Task(task) => serialize(&ruby, &task),
+            MavrikResponse::StoreState(state) => serialize(&ruby, &state),
+            MavrikResponse::DeadTasks(dead_tasks) => serialize(&ruby, &dead_tasks),
+            MavrikResponse::Schedules(schedules) => serialize(&ruby, &schedules),
+            MavrikResponse::Schedule(schedule) => serialize(&ruby, &schedule),
+            MavrikResponse::Count(count) => serialize(&ruby, &count),
+            MavrikResponse::Cancellation(state) => serialize(&ruby, &state),
+            MavrikResponse::PoolState(pool) => serialize(&ruby, &pool),
+            MavrikResponse::Error { code, message } => Err(server_error(code, message)),
+        }
     }
 
     #[inline]
     fn send(&self, req: &MavrikRequest) -> Result<MavrikResponse, anyhow::Error> {
         async_runtime().block_on(async move {
             self.tcp_client
-                .send(req)
+                .request(req)
                 .await
-                .context("sending request to server failed")?;
-            let res = self
-                .tcp_client
-                .recv()
-                .await
-                .context("receiving response from server failed")?;
-            Ok(res)
+                .context("requesting from server failed")
         })
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::io::{read_object, write_object};
-    use crate::messaging::{MavrikRequest, MavrikResponse};
+    use crate::io::{read_object, write_object, Encoding, DEFAULT_MAX_FRAME_SIZE};
+    use crate::messaging::{Capabilities, Envelope, Hello, MavrikRequest, MavrikResponse, Welcome};
     use crate::rb::connection::{define_connection, RbConnection, RbConnectionConfig};
     use crate::rb::util::{mavrik_error, module_mavrik};
     use crate::runtime::async_runtime;
@@ -105,9 +116,13 @@ pub mod tests {
         let config = RbConnectionConfig {
             host: Some(String::from(host)),
             port: Some(port),
+            max_frame_size: None,
         };
-        let handle =
-            set_up_listener(host, port, |(_, addr)| async move { addr }).map_err(mavrik_error)?;
+        let handle = set_up_listener(host, port, |(mut stream, addr)| async move {
+            answer_hello(&mut stream).await;
+            addr
+        })
+        .map_err(mavrik_error)?;
 
         let _ = RbConnection::new(serialize(ruby, &config)?)?;
 
@@ -123,6 +138,7 @@ pub mod tests {
         let config = RbConnectionConfig {
             host: Some(String::from(host)),
             port: Some(port),
+            max_frame_size: None,
         };
 
         let result = RbConnection::new(serialize(ruby, &config)?);
@@ -141,13 +157,17 @@ pub mod tests {
         let config = RbConnectionConfig {
             host: Some(String::from(host)),
             port: Some(port),
+            max_frame_size: None,
         };
         let handle = set_up_listener(host, port, |(mut stream, _)| async move {
-            let req: MavrikRequest = read_object(&mut stream).await.unwrap();
-            assert_eq!(req, MavrikRequest::GetStoreState);
-
-            let res = MavrikResponse::StoreState(StoreState { tasks: vec![] });
-            write_object(&mut stream, res).await.unwrap();
+            answer_hello(&mut stream).await;
+            let req: Envelope<MavrikRequest> =
+                read_object(&mut stream, Encoding::MessagePack, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+            assert_eq!(req.message, MavrikRequest::GetStoreState);
+
+            let res = MavrikResponse::StoreState(StoreState::default());
+            let res = Envelope { correlation_id: req.correlation_id, message: res };
+            write_object(&mut stream, Encoding::MessagePack, res).await.unwrap();
         })
         .map_err(mavrik_error)?;
 
@@ -159,6 +179,12 @@ pub mod tests {
         Ok(())
     }
 
+    async fn answer_hello(stream: &mut TcpStream) {
+        let hello: Hello = read_object(&mut *stream, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+        let capabilities = Capabilities { max_frame_size: DEFAULT_MAX_FRAME_SIZE, max_concurrent_requests: 64 };
+        write_object(stream, Encoding::Json, Welcome::new(&hello, capabilities)).await.unwrap();
+    }
+
     fn set_up_listener<T, F, Fut>(
         host: &str,
         port: u16,
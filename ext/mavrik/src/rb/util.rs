@@ -1,3 +1,4 @@
+use crate::messaging::ErrorCode;
 use magnus::error::RubyUnavailableError;
 use magnus::{ExceptionClass, Module, RModule, Ruby};
 use std::fmt::Debug;
@@ -46,6 +47,21 @@ where
     magnus::Error::new(class_mavrik_error(), message)
 }
 
+/// The error raised for a request the server failed, an instance of the `Mavrik::Error` subclass for its code.
+pub fn server_error(code: ErrorCode, message: String) -> magnus::Error {
+    let class = match code {
+        ErrorCode::InvalidRequest => "InvalidRequest",
+        ErrorCode::NotFound => "NotFound",
+        ErrorCode::StoreUnavailable => "StoreUnavailable",
+        ErrorCode::Overloaded => "Overloaded",
+        ErrorCode::Internal => "ServerError",
+    };
+    let class = module_mavrik()
+        .const_get::<_, ExceptionClass>(class)
+        .unwrap_or_else(|_| class_mavrik_error());
+    magnus::Error::new(class, message)
+}
+
 pub fn in_ruby<T>(mut func: impl FnMut(Ruby) -> T) -> T {
     match Ruby::get() {
         Ok(r) => func(r),
@@ -63,7 +79,8 @@ pub fn in_ruby<T>(mut func: impl FnMut(Ruby) -> T) -> T {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::rb::util::{class_mavrik_error, in_ruby, mavrik_error, module_mavrik};
+    use crate::messaging::ErrorCode;
+    use crate::rb::util::{class_mavrik_error, in_ruby, mavrik_error, module_mavrik, server_error};
     use anyhow::anyhow;
     use magnus::error::ErrorType;
     use magnus::value::ReprValue;
@@ -95,6 +112,20 @@ pub mod tests {
         }
     }
 
+    pub fn server_error_uses_class_for_code(_r: &Ruby) -> Result<(), magnus::Error> {
+        let error = server_error(ErrorCode::StoreUnavailable, "store push failed".to_string());
+
+        match error.error_type() {
+            ErrorType::Error(e, m) => {
+                assert_eq!(e.to_string(), "Mavrik::StoreUnavailable");
+                assert_eq!(m, "store push failed");
+                Ok(())
+            },
+
+            e => panic!("Expected ErrorType::Error, got {e:?}")
+        }
+    }
+
     pub fn in_ruby_calls_fn_in_gvl(_r: &Ruby) -> Result<(), magnus::Error> {
         let mut called = false;
         let called_ref = &mut called;
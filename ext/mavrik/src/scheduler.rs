@@ -0,0 +1,163 @@
+use crate::mavrik::MavrikOptions;
+use crate::messaging::{RecurringSchedule, Task, TaskId};
+use crate::service::ServiceTask;
+use crate::store::{ScheduleStore, StoredSchedule};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use log::{debug, info, warn};
+use std::time::{Duration, SystemTime};
+use tokio::time::{sleep_until, Instant};
+
+/// The longest the scheduler waits before checking the store again, so schedules added while it's waiting are noticed.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Service that submits tasks for recurring schedules when their cron expressions come due.
+///
+/// Schedules are kept in the store along with when they last fired, and each occurrence is claimed in the store before
+/// its task is submitted. An occurrence doesn't fire again after a restart, and occurrences missed while the server was
+/// stopped are submitted once rather than for each missed occurrence.
+///
+pub struct Scheduler<Store> {
+    store: Store,
+    next_check: Instant,
+}
+
+impl<Store> Scheduler<Store>
+where
+    Store: ScheduleStore<Id = TaskId, Error = anyhow::Error>
+{
+    /// Create a new scheduler service, saving the schedules from the options to the store.
+    ///
+    /// # Arguments
+    ///
+    /// `options` - Options for configuring the scheduler.
+    /// `store` - The store schedules are kept in and tasks are submitted to.
+    ///
+    pub async fn new(options: &MavrikOptions, store: Store) -> Result<Self, anyhow::Error> {
+        for schedule in options.schedules.clone().unwrap_or_default() {
+            validate(&schedule)?;
+            store.save_schedule(schedule).await?;
+        }
+
+        Ok(Self { store, next_check: Instant::now() })
+    }
+}
+
+impl<Store> ServiceTask for Scheduler<Store>
+where
+    Store: ScheduleStore<Id = TaskId, Error = anyhow::Error>
+{
+    type ReadyTask = Result<Vec<StoredSchedule>, anyhow::Error>;
+
+    async fn poll_task(&mut self) -> Self::ReadyTask {
+        sleep_until(self.next_check).await;
+        self.store.schedules().await
+    }
+
+    async fn on_task_ready(&mut self, schedules: Self::ReadyTask) -> Result<(), anyhow::Error> {
+        let now = SystemTime::now();
+        let mut next_check = now + POLL_INTERVAL;
+
+        // Store failures are retried on the next poll rather than stopping the server.
+        let schedules = match schedules {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                warn!(e:?; "Loading schedules failed");
+                vec![]
+            }
+        };
+
+        for stored in schedules {
+            let schedule = &stored.schedule;
+            let due_at = match next_occurrence(&schedule.cron, stored.last_fired()) {
+                Ok(Some(due_at)) => due_at,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(name = schedule.name, e:?; "Skipping schedule with invalid cron expression");
+                    continue;
+                }
+            };
+
+            if due_at > now {
+                next_check = next_check.min(due_at);
+                continue;
+            }
+
+            match self.store.fire_schedule(&schedule.name, due_at, Task::from(schedule)).await {
+                Ok(Some(task_id)) => info!(name = schedule.name, task_id:?; "Submitted task for schedule"),
+                Ok(None) => debug!(name = schedule.name; "Schedule already fired"),
+                Err(e) => warn!(name = schedule.name, e:?; "Firing schedule failed"),
+            }
+        }
+
+        let wait = next_check.duration_since(SystemTime::now()).unwrap_or_default();
+        self.next_check = Instant::now() + wait;
+        Ok(())
+    }
+}
+
+/// Check that a schedule's cron expression can be parsed.
+pub fn validate(schedule: &RecurringSchedule) -> Result<(), anyhow::Error> {
+    parse(&schedule.cron).with_context(|| format!("invalid cron expression for schedule '{}'", schedule.name))?;
+    Ok(())
+}
+
+/// When a cron expression next comes due, evaluated in UTC.
+///
+/// # Arguments
+///
+/// `cron` - A cron expression, with five fields or six if seconds are included.
+/// `after` - The time to look for the next occurrence after.
+///
+/// # Returns
+///
+/// The next occurrence strictly after `after`, or `None` if the expression never comes due again.
+///
+pub fn next_occurrence(cron: &str, after: SystemTime) -> Result<Option<SystemTime>, anyhow::Error> {
+    let after = DateTime::<Utc>::from(after);
+    match parse(cron)?.find_next_occurrence(&after, false) {
+        Ok(next) => Ok(Some(next.into())),
+        Err(croner::errors::CronError::TimeSearchLimitExceeded) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse(cron: &str) -> Result<Cron, anyhow::Error> {
+    Cron::new(cron).with_seconds_optional().parse().map_err(|e| anyhow!("{e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TasksInMemory;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn next_occurrence_is_strictly_after_the_given_time() -> Result<(), anyhow::Error> {
+        assert_eq!(next_occurrence("*/5 * * * *", at(0))?, Some(at(300)));
+        assert_eq!(next_occurrence("*/5 * * * *", at(299))?, Some(at(300)));
+        assert_eq!(next_occurrence("*/5 * * * *", at(300))?, Some(at(600)));
+        assert_eq!(next_occurrence("30 * * * * *", at(0))?, Some(at(30)));
+        Ok(())
+    }
+
+    #[test]
+    fn next_occurrence_rejects_invalid_expressions() {
+        assert!(next_occurrence("not a cron", at(0)).is_err());
+        assert!(next_occurrence("61 * * * *", at(0)).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn store_failures_are_retried_on_the_next_poll() -> Result<(), anyhow::Error> {
+        let mut scheduler = Scheduler { store: TasksInMemory::new(), next_check: Instant::now() };
+
+        scheduler.on_task_ready(Err(anyhow!("store unavailable"))).await?;
+        let wait = scheduler.next_check.duration_since(Instant::now());
+        assert!(wait > Duration::ZERO && wait <= POLL_INTERVAL);
+        Ok(())
+    }
+}
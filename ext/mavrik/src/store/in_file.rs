@@ -0,0 +1,934 @@
+//!
+//! A durable implementation of storing tasks, backed by a write-ahead log on disk.
+//!
+//! Tasks are kept in memory using `TasksInMemory`, but every push, dequeue, result publish and result pull is first
+//! appended to a log file. Opening the store replays the log to rebuild the in-memory state, so tasks survive the
+//! application stopping or crashing. Tasks that were being processed when the application stopped are enqueued again.
+//!
+//! Records are logged as length-prefixed MessagePack, with task values and outputs logged as the same bytes they're
+//! kept in memory with.
+//!
+//! Dequeued tasks are leased until their result is published. Tasks whose lease expires are returned to their queue.
+//!
+//! If a retention period is given, results that aren't pulled within that period are removed. Dead tasks are kept
+//! until they're re-enqueued or purged.
+//!
+//! Recurring schedules are logged along with every time they fire, so a schedule doesn't fire twice for the same
+//! occurrence after the application restarts.
+//!
+
+use crate::messaging::{RawBytes, RecurringSchedule, TaskId};
+use crate::store::store_state::{DeadTask, StoreState, StoredSchedule, StoredTask, StoredTaskStatus};
+use crate::store::{
+    Cancellation, ProcessStore, PullStore, PushStore, QueryStore, QueueOrder, ScheduleStore, TasksInMemory,
+};
+use crate::store::in_memory::{decode, encode, Claimed};
+use anyhow::Context;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::spawn_blocking;
+
+/// How many records are appended to the log between compactions.
+const COMPACT_EVERY: usize = 10_000;
+
+/// A single entry in the write-ahead log.
+///
+/// Values and outputs are serialized the same way as in `TasksInMemory`, so they're logged without serializing them
+/// again.
+///
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LogRecord {
+    Push { id: TaskId, queue: String, value: RawBytes },
+    Schedule { id: TaskId, queue: String, value: RawBytes, run_at: u64 },
+    Dequeue { id: TaskId },
+    Publish { id: TaskId, output: RawBytes, completed_at: u64 },
+    Retry { id: TaskId, value: RawBytes, available_at: u64 },
+    DeadLetter { id: TaskId, output: RawBytes, failed_at: u64 },
+    Requeue { id: TaskId, value: RawBytes },
+    Pull { id: TaskId },
+    Expire { id: TaskId },
+    Purge { id: TaskId },
+    SaveSchedule { schedule: StoredSchedule },
+    RemoveSchedule { name: String },
+    FireSchedule { name: String, fired_at: u64, id: TaskId, queue: String, value: RawBytes },
+}
+
+/// A task rebuilt from replaying the log.
+#[derive(Debug)]
+struct ReplayedTask {
+    queue: String,
+    value: RawBytes,
+    waiting: Option<(SystemTime, StoredTaskStatus)>,
+    output: Option<(RawBytes, SystemTime)>,
+    dead: bool,
+}
+
+/// Everything rebuilt from replaying the log.
+#[derive(Debug, Default)]
+struct Replayed {
+    tasks: BTreeMap<TaskId, ReplayedTask>,
+    schedules: BTreeMap<String, StoredSchedule>,
+    requeued: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TasksInFile {
+    tasks: TasksInMemory,
+    path: PathBuf,
+    log: Arc<Mutex<File>>,
+    appended: Arc<AtomicUsize>,
+    retention: Option<Duration>,
+}
+
+impl TasksInFile {
+    /// Open a file-backed store, replaying the log at `path` if it exists.
+    ///
+    /// The log is compacted after replaying so it only contains records for tasks that are still stored, and again
+    /// every `COMPACT_EVERY` records appended after that.
+    ///
+    /// Opening reads and rewrites the whole log, so async callers should open the store on a blocking thread.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - The path of the log file.
+    /// `order` - The order tasks are pulled from each queue.
+    /// `retention` - How long to keep results that haven't been pulled, or `None` to keep them forever.
+    ///
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        order: QueueOrder,
+        retention: Option<Duration>,
+    ) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let mut replayed = replay(path).with_context(|| format!("replaying task log {}", path.display()))?;
+        if let Some(cutoff) = expiry_cutoff(retention) {
+            replayed.tasks.retain(|_, task| task.dead || !matches!(task.output, Some((_, completed_at)) if completed_at < cutoff));
+        }
+        let log = compact(path, &replayed).with_context(|| format!("compacting task log {}", path.display()))?;
+        info!(
+            tasks = replayed.tasks.len(),
+            requeued = replayed.requeued,
+            schedules = replayed.schedules.len();
+            "Replayed task log"
+        );
+
+        let tasks = TasksInMemory::with_order(order);
+        for (_, schedule) in replayed.schedules {
+            tasks.restore_schedule(schedule);
+        }
+        for (id, task) in replayed.tasks {
+            let value = task.value.into();
+            match (task.output.map(|(output, at)| (output.into(), at)), task.waiting) {
+                (Some((output, failed_at)), _) if task.dead => tasks.bury(id, &task.queue, value, output, failed_at),
+                (Some((output, completed_at)), _) => tasks.complete(id, &task.queue, value, output, completed_at),
+                (None, Some((available_at, status))) => {
+                    let delay = available_at.duration_since(SystemTime::now()).unwrap_or_default();
+                    tasks.defer(id, &task.queue, value, delay, status)?;
+                }
+                (None, None) => tasks.enqueue(id, &task.queue, value)?,
+            }
+        }
+
+        Ok(Self {
+            tasks,
+            path: path.to_path_buf(),
+            log: Arc::new(Mutex::new(log)),
+            appended: Arc::new(AtomicUsize::new(0)),
+            retention,
+        })
+    }
+
+    /// Set how long a dequeued task can go without its result being published before it's returned to its queue.
+    pub fn with_lease_timeout(mut self, lease_timeout: Duration) -> Self {
+        self.tasks = self.tasks.with_lease_timeout(lease_timeout);
+        self
+    }
+
+    /// Lock the log, so records written while it's held are applied in memory in the same order they're logged.
+    async fn lock_log(&self) -> OwnedMutexGuard<File> {
+        self.log.clone().lock_owned().await
+    }
+
+    async fn append(&self, record: LogRecord) -> Result<(), anyhow::Error> {
+        self.write_records(self.lock_log().await, vec![record]).await?;
+        Ok(())
+    }
+
+    /// Append records to the log and sync it to disk on a blocking thread, so the runtime isn't held up by the disk.
+    ///
+    /// Once `COMPACT_EVERY` records have been appended since the last compaction, the log is compacted as well.
+    ///
+    /// # Returns
+    ///
+    /// The log, still locked, so the records can be applied in memory before anything else is logged.
+    ///
+    async fn write_records(
+        &self,
+        mut log: OwnedMutexGuard<File>,
+        records: Vec<LogRecord>,
+    ) -> Result<OwnedMutexGuard<File>, anyhow::Error> {
+        let appended = self.appended.fetch_add(records.len(), Ordering::Relaxed) + records.len();
+        let compact_due = appended >= COMPACT_EVERY;
+        if compact_due {
+            self.appended.store(0, Ordering::Relaxed);
+        }
+
+        let path = self.path.clone();
+        spawn_blocking(move || {
+            for record in &records {
+                write_record(&mut log, record)?;
+            }
+            log.sync_data().context("syncing task log")?;
+
+            // The records are already safely logged, so a failed compaction only leaves the log longer than it needs
+            // to be.
+            if compact_due {
+                match replay(&path).and_then(|replayed| compact(&path, &replayed)) {
+                    Ok(compacted) => *log = compacted,
+                    Err(e) => warn!(e:?; "Failed to compact task log"),
+                }
+            }
+            Ok(log)
+        })
+        .await
+        .context("writing task log")?
+    }
+
+    /// Log a record acknowledging a task being processed, then apply it in memory with `apply`.
+    ///
+    /// Acknowledgements of tasks that are no longer being processed, e.g. because another attempt already acknowledged
+    /// them, are ignored rather than logged.
+    ///
+    async fn acknowledge(
+        &self,
+        id: TaskId,
+        record: LogRecord,
+        apply: impl FnOnce(&TasksInMemory) -> Result<(), anyhow::Error> + Send,
+    ) -> Result<(), anyhow::Error> {
+        let log = self.lock_log().await;
+        if !self.tasks.can_acknowledge(id) {
+            warn!(id; "Ignoring acknowledgement of task that's no longer being processed");
+            return Ok(());
+        }
+
+        let _log = self.write_records(log, vec![record]).await?;
+        apply(&self.tasks)
+    }
+}
+
+impl PushStore for TasksInFile {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn push<S, V>(&self, queue: S, value: V) -> Result<Self::Id, Self::Error>
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send,
+    {
+        let queue = queue.as_ref();
+        let value = encode(&value)?;
+
+        // Hold the log while enqueueing so a dequeue can't be logged before the push it depends on.
+        let log = self.lock_log().await;
+        let id = TaskId::generate();
+        let record = LogRecord::Push { id, queue: queue.to_string(), value: value.clone().into() };
+        let _log = self.write_records(log, vec![record]).await?;
+
+        self.tasks.enqueue(id, queue, value)?;
+        Ok(id)
+    }
+
+    async fn schedule<S, V>(&self, queue: S, value: V, run_at: SystemTime) -> Result<Self::Id, Self::Error>
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send,
+    {
+        let queue = queue.as_ref();
+        let value = encode(&value)?;
+
+        let log = self.lock_log().await;
+        let id = TaskId::generate();
+        let record = LogRecord::Schedule {
+            id,
+            queue: queue.to_string(),
+            value: value.clone().into(),
+            run_at: millis(run_at),
+        };
+        let _log = self.write_records(log, vec![record]).await?;
+
+        let delay = run_at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.tasks.defer(id, queue, value, delay, StoredTaskStatus::Scheduled)?;
+        Ok(id)
+    }
+}
+
+impl PullStore for TasksInFile {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn pull<D>(&self, id: Self::Id) -> Result<D, Self::Error>
+    where
+        D: DeserializeOwned,
+    {
+        let (output, removed) = self.tasks.pull_raw(id).await?;
+        if removed {
+            self.append(LogRecord::Pull { id }).await?;
+        }
+
+        let output = decode(&output)?;
+        Ok(output)
+    }
+}
+
+impl ProcessStore for TasksInFile {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn dequeue<D>(&self, queues: &[String]) -> Result<(Self::Id, String, D), Self::Error>
+    where
+        D: DeserializeOwned,
+    {
+        let (id, queue, value) = self.tasks.dequeue_raw(queues).await?;
+
+        // The task goes back to its queue if this is dropped or fails before the task is handed over.
+        let claimed = Claimed::new(&self.tasks, id);
+        self.append(LogRecord::Dequeue { id }).await?;
+        let value = decode(&value)?;
+        claimed.deliver();
+        Ok((id, queue, value))
+    }
+
+    async fn publish_result<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        let completed_at = millis(SystemTime::now());
+        let record = LogRecord::Publish { id, output: output.clone().into(), completed_at };
+        self.acknowledge(id, record, |tasks| {
+            tasks.publish_raw(id, output);
+            Ok(())
+        })
+        .await?;
+
+        if let Some(cutoff) = expiry_cutoff(self.retention) {
+            let log = self.lock_log().await;
+            let expired = self.tasks.expire_completed(cutoff);
+            if !expired.is_empty() {
+                self.write_records(log, expired.into_iter().map(|id| LogRecord::Expire { id }).collect()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn retry<V>(&self, id: Self::Id, value: V, delay: Duration) -> Result<(), Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let available_at = millis(SystemTime::now() + delay);
+        let record = LogRecord::Retry { id, value: value.clone().into(), available_at };
+        self.acknowledge(id, record, |tasks| tasks.retry_raw(id, value, delay)).await
+    }
+
+    async fn dead_letter<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        let failed_at = millis(SystemTime::now());
+        let record = LogRecord::DeadLetter { id, output: output.clone().into(), failed_at };
+        self.acknowledge(id, record, |tasks| {
+            tasks.dead_letter_raw(id, output);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn renew_leases(&self, ids: &[Self::Id]) -> Result<(), Self::Error> {
+        // Leases aren't logged, since tasks that were being processed are enqueued again on replay.
+        self.tasks.renew_leases_raw(ids);
+        Ok(())
+    }
+
+    async fn postpone(&self, id: Self::Id, delay: Duration) -> Result<(), Self::Error> {
+        // Like leases, postponements aren't logged, since the task is enqueued again on replay all the same.
+        let _log = self.lock_log().await;
+        self.tasks.postpone_raw(id, delay);
+        Ok(())
+    }
+}
+
+impl QueryStore for TasksInFile {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn state(&self) -> Result<StoreState, Self::Error> {
+        self.tasks.state().await
+    }
+
+    async fn enqueued(&self, queues: &[String]) -> Result<usize, Self::Error> {
+        self.tasks.enqueued(queues).await
+    }
+
+    async fn task(&self, id: Self::Id) -> Result<Option<StoredTask>, Self::Error> {
+        self.tasks.task(id).await
+    }
+
+    async fn dead_tasks(&self) -> Result<Vec<DeadTask>, Self::Error> {
+        self.tasks.dead_tasks().await
+    }
+
+    async fn requeue_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        // Hold the log so a dequeue of a re-enqueued task can't be logged before its requeue.
+        let log = self.lock_log().await;
+        let requeued = self.tasks.requeue_dead_raw(ids)?;
+        let records = requeued
+            .iter()
+            .map(|(id, task)| Ok(LogRecord::Requeue { id: *id, value: encode(task)?.into() }))
+            .collect::<Result<_, anyhow::Error>>()?;
+        self.write_records(log, records).await?;
+        Ok(requeued.len())
+    }
+
+    async fn purge_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        let log = self.lock_log().await;
+        let purged = self.tasks.purge_dead_raw(ids);
+        self.write_records(log, purged.iter().map(|id| LogRecord::Purge { id: *id }).collect()).await?;
+        Ok(purged.len())
+    }
+
+    async fn cancel<S>(&self, id: Self::Id, output: S) -> Result<Cancellation, Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+
+        // A cancelled task replays like one whose result was published without being processed.
+        let log = self.lock_log().await;
+        let cancellation = self.tasks.cancel_raw(id, output.clone());
+        if cancellation == Cancellation::Cancelled {
+            let completed_at = millis(SystemTime::now());
+            self.write_records(log, vec![LogRecord::Publish { id, output: output.into(), completed_at }]).await?;
+        }
+        Ok(cancellation)
+    }
+}
+
+impl ScheduleStore for TasksInFile {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn schedules(&self) -> Result<Vec<StoredSchedule>, Self::Error> {
+        self.tasks.schedules().await
+    }
+
+    async fn save_schedule(&self, schedule: RecurringSchedule) -> Result<StoredSchedule, Self::Error> {
+        let log = self.lock_log().await;
+        let stored = self.tasks.save_schedule_raw(schedule);
+        self.write_records(log, vec![LogRecord::SaveSchedule { schedule: stored.clone() }]).await?;
+        Ok(stored)
+    }
+
+    async fn remove_schedule(&self, name: &str) -> Result<bool, Self::Error> {
+        let log = self.lock_log().await;
+        let removed = self.tasks.remove_schedule_raw(name);
+        if removed {
+            self.write_records(log, vec![LogRecord::RemoveSchedule { name: name.to_string() }]).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn fire_schedule<V>(&self, name: &str, due_at: SystemTime, value: V) -> Result<Option<Self::Id>, Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+
+        // Log the fire and the task it submits as one record so a crash can't keep one without the other.
+        let log = self.lock_log().await;
+        // Only mark the occurrence as fired once it's logged, so a failed write doesn't skip it.
+        let Some(stored) = self.tasks.next_firing(name, due_at) else {
+            return Ok(None);
+        };
+
+        let id = TaskId::generate();
+        let queue = stored.schedule.queue.clone();
+        let record = LogRecord::FireSchedule {
+            name: name.to_string(),
+            fired_at: stored.last_fired_at,
+            id,
+            queue: queue.clone(),
+            value: value.clone().into(),
+        };
+        let _log = self.write_records(log, vec![record]).await?;
+
+        self.tasks.restore_schedule(stored);
+        self.tasks.enqueue(id, &queue, value)?;
+        Ok(Some(id))
+    }
+}
+
+fn write_record(log: &mut File, record: &LogRecord) -> Result<(), anyhow::Error> {
+    let payload = encode(record).context("serializing task log record")?;
+    let length = u32::try_from(payload.len()).context("task log record too large")?;
+
+    // Write the record in one go, so crashing mid-write can only leave a partial record at the end of the log.
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    log.write_all(&frame).context("writing task log record")?;
+    Ok(())
+}
+
+/// Rebuild the stored tasks and schedules from the log at `path`.
+///
+/// Tasks that were dequeued but never had a result published are returned as enqueued. Tasks waiting to be retried
+/// keep the time they become available again.
+///
+fn replay(path: &Path) -> Result<Replayed, anyhow::Error> {
+    let Replayed { mut tasks, mut schedules, .. } = Replayed::default();
+    let log = match fs::read(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Replayed::default()),
+        Err(e) => return Err(e).context("reading task log"),
+    };
+    let records = read_records(&log)?;
+
+    let mut processing = HashSet::new();
+    for record in records {
+        match record {
+            LogRecord::Push { id, queue, value } => {
+                tasks.insert(id, ReplayedTask { queue, value, waiting: None, output: None, dead: false });
+            }
+
+            LogRecord::Schedule { id, queue, value, run_at } => {
+                let waiting = Some((from_millis(run_at), StoredTaskStatus::Scheduled));
+                tasks.insert(id, ReplayedTask { queue, value, waiting, output: None, dead: false });
+            }
+
+            LogRecord::Dequeue { id } => {
+                processing.insert(id);
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.waiting = None;
+                }
+            }
+
+            LogRecord::Retry { id, value, available_at } => {
+                processing.remove(&id);
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.value = value;
+                    task.waiting = Some((from_millis(available_at), StoredTaskStatus::Retrying));
+                }
+            }
+
+            LogRecord::Publish { id, output, completed_at } => {
+                processing.remove(&id);
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.output = Some((output, from_millis(completed_at)));
+                }
+            }
+
+            LogRecord::DeadLetter { id, output, failed_at } => {
+                processing.remove(&id);
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.output = Some((output, from_millis(failed_at)));
+                    task.dead = true;
+                }
+            }
+
+            LogRecord::Requeue { id, value } => {
+                if let Some(task) = tasks.get_mut(&id) {
+                    task.value = value;
+                    task.output = None;
+                    task.dead = false;
+                }
+            }
+
+            LogRecord::Pull { id } | LogRecord::Expire { id } | LogRecord::Purge { id } => {
+                tasks.remove(&id);
+            }
+
+            LogRecord::SaveSchedule { schedule } => {
+                schedules.insert(schedule.schedule.name.clone(), schedule);
+            }
+
+            LogRecord::RemoveSchedule { name } => {
+                schedules.remove(&name);
+            }
+
+            LogRecord::FireSchedule { name, fired_at, id, queue, value } => {
+                if let Some(schedule) = schedules.get_mut(&name) {
+                    schedule.last_fired_at = fired_at;
+                }
+                tasks.insert(id, ReplayedTask { queue, value, waiting: None, output: None, dead: false });
+            }
+        }
+    }
+
+    processing.retain(|id| tasks.contains_key(id));
+    Ok(Replayed { tasks, schedules, requeued: processing.len() })
+}
+
+/// Read the length-prefixed MessagePack records of a log.
+fn read_records(mut framed: &[u8]) -> Result<Vec<LogRecord>, anyhow::Error> {
+    let mut records = vec![];
+    while !framed.is_empty() {
+        let payload = framed.get(..4).and_then(|length| {
+            let length = u32::from_be_bytes(length.try_into().ok()?) as usize;
+            framed.get(4..4 + length)
+        });
+
+        // Crashing mid-write can leave a partial record at the end of the log.
+        let Some(payload) = payload else {
+            warn!(bytes = framed.len(); "Ignoring incomplete record at end of task log");
+            break;
+        };
+
+        records.push(decode(payload).context("deserializing task log record")?);
+        framed = &framed[4 + payload.len()..];
+    }
+    Ok(records)
+}
+
+/// Rewrite the log at `path` to contain only the given tasks and schedules, returning the log to append to.
+fn compact(path: &Path, replayed: &Replayed) -> Result<File, anyhow::Error> {
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.as_mut_os_string().push(".tmp");
+
+    let mut tmp = File::create(&tmp_path).context("creating compacted task log")?;
+    for schedule in replayed.schedules.values() {
+        write_record(&mut tmp, &LogRecord::SaveSchedule { schedule: schedule.clone() })?;
+    }
+    for (id, task) in &replayed.tasks {
+        let (id, queue, value) = (*id, task.queue.clone(), task.value.clone());
+        match task.waiting {
+            Some((run_at, StoredTaskStatus::Scheduled)) => {
+                write_record(&mut tmp, &LogRecord::Schedule { id, queue, value, run_at: millis(run_at) })?;
+            }
+            Some((available_at, _)) => {
+                write_record(&mut tmp, &LogRecord::Push { id, queue, value: value.clone() })?;
+                write_record(&mut tmp, &LogRecord::Retry { id, value, available_at: millis(available_at) })?;
+            }
+            None => {
+                write_record(&mut tmp, &LogRecord::Push { id, queue, value })?;
+            }
+        }
+
+        match &task.output {
+            Some((output, failed_at)) if task.dead => {
+                let failed_at = millis(*failed_at);
+                write_record(&mut tmp, &LogRecord::DeadLetter { id, output: output.clone(), failed_at })?;
+            }
+            Some((output, completed_at)) => {
+                let completed_at = millis(*completed_at);
+                write_record(&mut tmp, &LogRecord::Publish { id, output: output.clone(), completed_at })?;
+            }
+            None => {}
+        }
+    }
+    tmp.sync_all().context("syncing compacted task log")?;
+
+    // Keep appending through the compacted log's own handle, so there's no window where the log has been replaced
+    // but can't be reopened.
+    fs::rename(&tmp_path, path).context("replacing task log")?;
+    Ok(tmp)
+}
+
+/// When results must have completed by to still be kept, or `None` if they're kept forever.
+///
+/// A retention reaching back before the Unix epoch keeps results forever rather than overflowing.
+///
+fn expiry_cutoff(retention: Option<Duration>) -> Option<SystemTime> {
+    SystemTime::now().checked_sub(retention?)
+}
+
+/// Milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::{Task, TaskResult};
+    use std::fs::OpenOptions;
+
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mavrik-{}-{name}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn success(result: &str) -> TaskResult {
+        TaskResult::Success { result: result.to_string() }
+    }
+
+    #[tokio::test]
+    async fn reopening_restores_enqueued_tasks_in_order() -> Result<(), anyhow::Error> {
+        let path = log_path("restores-enqueued");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let first_id = store.push("default", Task::for_test("First")).await?;
+        let second_id = store.push("default", Task::for_test("Second")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let queues = vec!["default".to_string()];
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (first_id, Task::for_test("First")));
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (second_id, Task::for_test("Second")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_requeues_tasks_that_were_processing() -> Result<(), anyhow::Error> {
+        let path = log_path("requeues-processing");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Interrupted")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        let stored = &state.queues["default"];
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, task_id);
+        assert_eq!(stored[0].status, StoredTaskStatus::Enqueued);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_dequeue_returns_task_to_its_queue() -> Result<(), anyhow::Error> {
+        let path = log_path("dropped-dequeue");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Dropped")).await?;
+
+        // The dequeue is dropped while it waits to log, after it has taken the task from its queue.
+        let log = store.lock_log().await;
+        assert!(futures::FutureExt::now_or_never(store.dequeue::<Task>(&["default".to_string()])).is_none());
+        drop(log);
+
+        let (id, _, _) = store.dequeue::<Task>(&["default".to_string()]).await?;
+        assert_eq!(id, task_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_results_until_pulled() -> Result<(), anyhow::Error> {
+        let path = log_path("keeps-results");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        store.publish_result(task_id, success("6")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let result: TaskResult = store.pull(task_id).await?;
+        assert_eq!(result, success("6"));
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        assert!(store.state().await?.queues.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_retried_tasks_waiting() -> Result<(), anyhow::Error> {
+        let path = log_path("keeps-retried");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Flaky")).await?;
+        let (_, _, mut value): (TaskId, String, Task) = store.dequeue(&["default".to_string()]).await?;
+        value.attempts += 1;
+        store.retry(task_id, value.clone(), Duration::from_secs(60)).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        let stored = &state.queues["default"];
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, task_id);
+        assert_eq!(stored[0].status, StoredTaskStatus::Retrying);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_scheduled_tasks_waiting() -> Result<(), anyhow::Error> {
+        let path = log_path("keeps-scheduled");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let run_at = SystemTime::now() + Duration::from_secs(60);
+        let task_id = store.schedule("default", Task::for_test("Later"), run_at).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"][0].id, task_id);
+        assert_eq!(state.queues["default"][0].status, StoredTaskStatus::Scheduled);
+        assert!(futures::FutureExt::now_or_never(store.dequeue::<Task>(&["default".to_string()])).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_dead_tasks_until_purged() -> Result<(), anyhow::Error> {
+        let path = log_path("keeps-dead");
+        let failure = TaskResult::Failure { class: "RuntimeError".to_string(), message: "oops".to_string(), backtrace: vec![] };
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, Some(Duration::ZERO))?;
+        let task_id = store.push("default", Task::for_test("Broken")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        store.dead_letter(task_id, failure.clone()).await?;
+        assert_eq!(store.pull::<TaskResult>(task_id).await?, failure);
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, Some(Duration::ZERO))?;
+        let dead_tasks = store.dead_tasks().await?;
+        assert_eq!(dead_tasks.len(), 1);
+        assert_eq!(dead_tasks[0].task.id, task_id);
+        assert_eq!(dead_tasks[0].failure, failure);
+        assert_eq!(store.purge_dead(None).await?, 1);
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        assert!(store.state().await?.queues.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn results_are_expired_after_retention() -> Result<(), anyhow::Error> {
+        let path = log_path("expires-results");
+        let retention = Some(Duration::from_millis(200));
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, retention)?;
+        let old_id = store.push("default", Task::for_test("Old")).await?;
+        let new_id = store.push("default", Task::for_test("New")).await?;
+        let queues = vec!["default".to_string()];
+        store.dequeue::<Task>(&queues).await?;
+        store.dequeue::<Task>(&queues).await?;
+
+        store.publish_result(old_id, success("1")).await?;
+        std::thread::sleep(Duration::from_millis(300));
+        store.publish_result(new_id, success("2")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, retention)?;
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"].len(), 1);
+        assert_eq!(state.queues["default"][0].id, new_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn log_is_compacted_after_enough_records() -> Result<(), anyhow::Error> {
+        let path = log_path("periodic-compaction");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        store.publish_result(task_id, success("6")).await?;
+
+        store.appended.store(COMPACT_EVERY - 1, Ordering::Relaxed);
+        store.pull::<TaskResult>(task_id).await?;
+        assert!(fs::read(&path)?.is_empty());
+
+        let kept_id = store.push("default", Task::for_test("Kept")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"].len(), 1);
+        assert_eq!(state.queues["default"][0].id, kept_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retention_longer_than_the_epoch_keeps_results() -> Result<(), anyhow::Error> {
+        let path = log_path("long-retention");
+        let retention = Some(Duration::from_secs(u64::MAX));
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, retention)?;
+        let task_id = store.push("default", Task::for_test("Kept")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        store.publish_result(task_id, success("1")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, retention)?;
+        assert_eq!(store.pull::<TaskResult>(task_id).await?, success("1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_ignores_incomplete_trailing_record() -> Result<(), anyhow::Error> {
+        let path = log_path("incomplete-record");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Kept")).await?;
+        drop(store);
+
+        // A record whose length made it to disk, but only part of its payload did.
+        let mut log = OpenOptions::new().append(true).open(&path)?;
+        log.write_all(&[0, 0, 0, 40, 0x83, 0xa4])?;
+        drop(log);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"].len(), 1);
+        assert_eq!(state.queues["default"][0].id, task_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_values_are_logged_as_they_are_kept_in_memory() -> Result<(), anyhow::Error> {
+        let path = log_path("binary-args");
+        let binary = Task { args: vec![0x92, 0x01, 0xff].into(), ..Task::for_test("Binary") };
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", binary.clone()).await?;
+        drop(store);
+
+        let log = fs::read(&path)?;
+        let value = encode(&binary)?;
+        assert!(log.windows(value.len()).any(|window| window == value));
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&["default".to_string()]).await?;
+        assert_eq!((id, value), (task_id, binary));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_when_schedules_last_fired() -> Result<(), anyhow::Error> {
+        let path = log_path("keeps-schedules");
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let schedule = RecurringSchedule {
+            name: "report".to_string(),
+            cron: "* * * * *".to_string(),
+            queue: "reports".to_string(),
+            definition: "Report".to_string(),
+            args: "[]".into(),
+            kwargs: "{}".into(),
+        };
+        let added = store.save_schedule(schedule.clone()).await?;
+        let due_at = added.last_fired() + Duration::from_secs(60);
+        let task_id = store.fire_schedule("report", due_at, Task::for_test("Report")).await?;
+        drop(store);
+
+        let store = TasksInFile::open(&path, QueueOrder::Fifo, None)?;
+        let schedules = store.schedules().await?;
+        assert_eq!(schedules.len(), 1);
+        assert_eq!(schedules[0].schedule, schedule);
+        assert!(store.fire_schedule("report", due_at, Task::for_test("Report")).await?.is_none());
+
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&["reports".to_string()]).await?;
+        assert_eq!(Some(id), task_id);
+        Ok(())
+    }
+}
@@ -6,64 +6,596 @@
 //! execution logic from the logic of managing task queues and results.
 //!
 
-use crate::messaging::{Task, TaskId};
-use crate::store::store_state::{StoreState, StoredTask, StoredTaskStatus};
-use crate::store::{ProcessStore, PullStore, PushStore, QueryStore};
-use log::trace;
+use crate::io::{self, Encoding};
+use crate::messaging::{RecurringSchedule, Task, TaskId};
+use crate::store::store_state::{DeadTask, StoreState, StoredSchedule, StoredTask, StoredTaskStatus};
+use crate::store::{
+    Cancellation, ProcessStore, PullStore, PushStore, QueryStore, QueueOrder, ScheduleStore, DEFAULT_LEASE_TIMEOUT,
+};
+use log::{debug, trace, warn};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::future::Future;
-use std::ops::DerefMut;
-use std::pin::{pin, Pin};
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
-use std::time::SystemTime;
-use tokio::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// A stored task value along with the name of the queue it was pushed to.
+#[derive(Debug, Clone)]
+struct QueuedValue {
+    queue: String,
+    value: Vec<u8>,
+}
+
+/// A task being processed, along with when its lease expires and it's returned to its queue.
+#[derive(Debug, Clone)]
+struct LeasedValue {
+    queued: QueuedValue,
+    rank: Rank,
+    expires_at: Instant,
+}
+
+/// A task waiting to be returned to its queue, along with why it's waiting.
+#[derive(Debug, Clone)]
+struct WaitingValue {
+    queued: QueuedValue,
+    rank: Rank,
+    status: StoredTaskStatus,
+}
+
+/// The output of a processed task along with the task itself.
+#[derive(Debug, Clone)]
+struct CompletedValue {
+    queued: QueuedValue,
+    output: Vec<u8>,
+    completed_at: SystemTime,
+}
+
+/// Enqueued tasks, keyed by queue name.
+///
+/// Each queue is kept sorted from highest to lowest priority, then from oldest to newest task ID.
+type Queues = HashMap<String, VecDeque<Enqueued>>;
+
+/// An enqueued task, along with the parts of its value that decide when it's dequeued.
+#[derive(Debug, Clone)]
+struct Enqueued {
+    rank: Rank,
+    id: TaskId,
+    value: Vec<u8>,
+}
+
+/// The parts of a task value that decide when it's dequeued.
+///
+/// These are decoded once as the task enters the store, rather than each time a dequeue considers it.
+///
+#[derive(Debug, Clone)]
+struct Rank {
+    priority: i32,
+    concurrency: Option<(String, usize)>,
+}
+
+impl Rank {
+    fn of(value: &[u8]) -> Result<Self, anyhow::Error> {
+        let Ranked { priority, concurrency_key, concurrency_limit } = decode(value)?;
+        let concurrency = concurrency_key.map(|key| (key, concurrency_limit.unwrap_or(1) as usize));
+        Ok(Self { priority, concurrency })
+    }
+}
+
+/// The parts of a stored task value that decide where it goes in its queue, and how many tasks sharing its concurrency
+/// key are processed at once.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Ranked {
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub concurrency_key: Option<String>,
+    #[serde(default)]
+    pub concurrency_limit: Option<u32>,
+}
+
+/// Tasks waiting to be returned to their queues, ordered by when they become available.
+type Waiting = BTreeMap<(Instant, TaskId), WaitingValue>;
+
+/// Wakers of pulls waiting on each task's output, keyed by the ID of the pull that registered them.
+type CompletedWakers = HashMap<TaskId, HashMap<u64, Waker>>;
+
+/// The ID given to the next pull, so it can find its own waker among others waiting on the same task.
+static NEXT_PULL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Outputs of completed tasks, along with the order they completed in, so expired outputs are found without scanning
+/// every output.
+#[derive(Debug, Default)]
+struct Completed {
+    outputs: HashMap<TaskId, CompletedValue>,
+    by_time: BTreeSet<(SystemTime, TaskId)>,
+}
+
+impl Completed {
+    fn insert(&mut self, id: TaskId, completed: CompletedValue) {
+        self.remove(id);
+        self.by_time.insert((completed.completed_at, id));
+        self.outputs.insert(id, completed);
+    }
+
+    fn remove(&mut self, id: TaskId) -> Option<CompletedValue> {
+        let completed = self.outputs.remove(&id)?;
+        self.by_time.remove(&(completed.completed_at, id));
+        Some(completed)
+    }
+
+    fn get(&self, id: TaskId) -> Option<&CompletedValue> {
+        self.outputs.get(&id)
+    }
+
+    /// Remove the outputs of tasks completed before `before`, returning their IDs.
+    fn expire(&mut self, before: SystemTime) -> Vec<TaskId> {
+        let mut expired = vec![];
+        while let Some(&(completed_at, id)) = self.by_time.first() {
+            if completed_at >= before {
+                break;
+            }
+            self.by_time.pop_first();
+            self.outputs.remove(&id);
+            expired.push(id);
+        }
+        expired
+    }
+}
+
+/// Tasks being processed, along with when their leases expire and how many are running under each concurrency key.
+///
+/// The expiries and counts are kept up to date as tasks are leased and acknowledged, so a dequeue doesn't have to
+/// scan every task being processed.
+///
+#[derive(Debug, Default)]
+struct Busy {
+    leased: HashMap<TaskId, LeasedValue>,
+    expiries: BTreeSet<(Instant, TaskId)>,
+    running: HashMap<String, usize>,
+}
+
+impl Busy {
+    fn insert(&mut self, id: TaskId, leased: LeasedValue) {
+        self.remove(id);
+        if let Some((key, _)) = &leased.rank.concurrency {
+            *self.running.entry(key.clone()).or_default() += 1;
+        }
+        self.expiries.insert((leased.expires_at, id));
+        self.leased.insert(id, leased);
+    }
+
+    fn remove(&mut self, id: TaskId) -> Option<LeasedValue> {
+        let leased = self.leased.remove(&id)?;
+        self.forget(id, &leased);
+        Some(leased)
+    }
+
+    /// Drop a task that's no longer leased from the expiries and counts.
+    fn forget(&mut self, id: TaskId, leased: &LeasedValue) {
+        self.expiries.remove(&(leased.expires_at, id));
+        if let Some((key, _)) = &leased.rank.concurrency {
+            if let Some(count) = self.running.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    self.running.remove(key);
+                }
+            }
+        }
+    }
+
+    fn renew(&mut self, id: TaskId, expires_at: Instant) {
+        if let Some(leased) = self.leased.get_mut(&id) {
+            self.expiries.remove(&(leased.expires_at, id));
+            self.expiries.insert((expires_at, id));
+            leased.expires_at = expires_at;
+        }
+    }
+
+    fn get(&self, id: TaskId) -> Option<&LeasedValue> {
+        self.leased.get(&id)
+    }
+
+    fn contains(&self, id: TaskId) -> bool {
+        self.leased.contains_key(&id)
+    }
+
+    /// When the next lease expires, if any task is being processed.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.expiries.first().map(|(expires_at, _)| *expires_at)
+    }
+
+    /// Remove the tasks whose leases expired by `now`.
+    fn expire(&mut self, now: Instant) -> Vec<(TaskId, LeasedValue)> {
+        let mut expired = vec![];
+        while let Some(&(expires_at, id)) = self.expiries.first() {
+            if expires_at > now {
+                break;
+            }
+            match self.remove(id) {
+                Some(leased) => expired.push((id, leased)),
+                None => {
+                    self.expiries.pop_first();
+                }
+            }
+        }
+        expired
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TasksInMemory {
+    order: QueueOrder,
+    lease_timeout: Duration,
     queue_wakers: Arc<Mutex<Vec<Waker>>>,
-    queue: Arc<Mutex<Vec<(TaskId, String)>>>,
-    busy: Arc<Mutex<HashMap<TaskId, String>>>,
-    completed_wakers: Arc<Mutex<HashMap<TaskId, Waker>>>,
-    completed: Arc<Mutex<HashMap<TaskId, String>>>,
+    queues: Arc<Mutex<Queues>>,
+    busy: Arc<Mutex<Busy>>,
+    waiting: Arc<Mutex<Waiting>>,
+    completed_wakers: Arc<Mutex<CompletedWakers>>,
+    completed: Arc<Mutex<Completed>>,
+    dead: Arc<Mutex<HashMap<TaskId, CompletedValue>>>,
+    schedules: Arc<Mutex<BTreeMap<String, StoredSchedule>>>,
 }
 
 impl TasksInMemory {
     pub fn new() -> Self {
+        Self::with_order(QueueOrder::default())
+    }
+
+    /// Create a new in-memory store that pulls tasks from its queues in the given order.
+    pub fn with_order(order: QueueOrder) -> Self {
         Self {
+            order,
+            lease_timeout: DEFAULT_LEASE_TIMEOUT,
             queue_wakers: Arc::new(Mutex::new(Vec::new())),
-            queue: Arc::new(Mutex::new(Vec::new())),
-            busy: Arc::new(Mutex::new(HashMap::new())),
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            busy: Arc::new(Mutex::new(Busy::default())),
+            waiting: Arc::new(Mutex::new(BTreeMap::new())),
             completed_wakers: Arc::new(Mutex::new(HashMap::new())),
-            completed: Arc::new(Mutex::new(HashMap::new())),
+            completed: Arc::new(Mutex::new(Completed::default())),
+            dead: Arc::new(Mutex::new(HashMap::new())),
+            schedules: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 
-    fn next_id() -> TaskId {
-        // (timestamp, counter)
-        static LAST: std::sync::Mutex<(u128, u32)> = std::sync::Mutex::new((0, 0));
+    /// Set how long a dequeued task can go without its result being published before it's returned to its queue.
+    pub fn with_lease_timeout(mut self, lease_timeout: Duration) -> Self {
+        self.lease_timeout = lease_timeout;
+        self
+    }
 
-        let mut guard = LAST.lock().unwrap();
-        let (last_timestamp, last_count) = guard.deref_mut();
+    /// Enqueue a task value under an existing ID, keeping the queue sorted by priority and ID.
+    pub(crate) fn enqueue(&self, id: TaskId, queue: &str, value: Vec<u8>) -> Result<(), anyhow::Error> {
+        let rank = Rank::of(&value)?;
+        let queued = QueuedValue { queue: queue.to_string(), value };
+        requeue(&mut self.queues.lock().unwrap(), id, queued, rank);
 
-        // Use system timestamp as primary identifier
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+        self.wake_dequeuers();
+        Ok(())
+    }
 
-        // Append counter to end in case of conflict with timestamp.
-        if *last_timestamp == timestamp {
-            *last_count += 1;
-        } else {
-            *last_timestamp = timestamp;
-            *last_count = 0;
+    /// Hold a task value under an existing ID until `delay` has passed, then return it to its queue.
+    ///
+    /// # Arguments
+    ///
+    /// `status` - Why the task is waiting, either `Scheduled` or `Retrying`.
+    ///
+    pub(crate) fn defer(
+        &self,
+        id: TaskId,
+        queue: &str,
+        value: Vec<u8>,
+        delay: Duration,
+        status: StoredTaskStatus,
+    ) -> Result<(), anyhow::Error> {
+        let rank = Rank::of(&value)?;
+        let queued = QueuedValue { queue: queue.to_string(), value };
+        let available_at = Instant::now() + delay;
+        self.waiting.lock().unwrap().insert((available_at, id), WaitingValue { queued, rank, status });
+
+        // Dequeuers need to know when to check again.
+        self.wake_dequeuers();
+        Ok(())
+    }
+
+    /// Dequeuers may be waiting on different queues, so wake them all and let them check.
+    fn wake_dequeuers(&self) {
+        let mut wakers = self.queue_wakers.lock().unwrap();
+        for waker in wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Store the output of a task that has already been processed, making it available to be pulled.
+    pub(crate) fn complete(&self, id: TaskId, queue: &str, value: Vec<u8>, output: Vec<u8>, completed_at: SystemTime) {
+        let queued = QueuedValue { queue: queue.to_string(), value };
+        let completed = CompletedValue { queued, output, completed_at };
+        self.completed.lock().unwrap().insert(id, completed);
+    }
+
+    /// Store a task that has already failed for good in the dead-letter area, along with the output of its final attempt.
+    pub(crate) fn bury(&self, id: TaskId, queue: &str, value: Vec<u8>, output: Vec<u8>, failed_at: SystemTime) {
+        let queued = QueuedValue { queue: queue.to_string(), value };
+        let dead = CompletedValue { queued, output, completed_at: failed_at };
+        self.dead.lock().unwrap().insert(id, dead);
+    }
+
+    /// Store a schedule as it was, including when it last fired.
+    pub(crate) fn restore_schedule(&self, stored: StoredSchedule) {
+        self.schedules.lock().unwrap().insert(stored.schedule.name.clone(), stored);
+    }
+
+    /// Add a schedule, or replace the schedule with the same name while keeping when it last fired.
+    pub(crate) fn save_schedule_raw(&self, schedule: RecurringSchedule) -> StoredSchedule {
+        let mut schedules = self.schedules.lock().unwrap();
+        let stored = match schedules.get(&schedule.name) {
+            Some(existing) => StoredSchedule { schedule, last_fired_at: existing.last_fired_at },
+            None => StoredSchedule::new(schedule, SystemTime::now()),
+        };
+        schedules.insert(stored.schedule.name.clone(), stored.clone());
+        stored
+    }
+
+    /// Remove a schedule, returning whether it existed.
+    pub(crate) fn remove_schedule_raw(&self, name: &str) -> bool {
+        self.schedules.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Mark the occurrence of a schedule due at `due_at` as fired, unless it already has.
+    ///
+    /// # Returns
+    ///
+    /// The schedule as of firing, or `None` if the occurrence already fired or the schedule doesn't exist.
+    ///
+    pub(crate) fn claim_schedule(&self, name: &str, due_at: SystemTime) -> Option<StoredSchedule> {
+        let mut schedules = self.schedules.lock().unwrap();
+        let stored = schedules.get_mut(name)?;
+        *stored = fired(stored, due_at)?;
+        Some(stored.clone())
+    }
+
+    /// Like `claim_schedule`, but without marking the occurrence as fired, so it can be logged first.
+    pub(crate) fn next_firing(&self, name: &str, due_at: SystemTime) -> Option<StoredSchedule> {
+        fired(self.schedules.lock().unwrap().get(name)?, due_at)
+    }
+
+    /// Remove the outputs of tasks completed before `before` that were never pulled.
+    ///
+    /// # Returns
+    ///
+    /// The IDs of the removed tasks.
+    ///
+    pub(crate) fn expire_completed(&self, before: SystemTime) -> Vec<TaskId> {
+        self.completed.lock().unwrap().expire(before)
+    }
+
+    /// Wait for the next task from one of the given queues, returning the queue it came from and its serialized value.
+    pub(crate) async fn dequeue_raw(&self, queues: &[String]) -> Result<(TaskId, String, Vec<u8>), anyhow::Error> {
+        let (id, queue, value) = NextTask::new(queues, self).await?;
+        trace!(id, queue, bytes = value.len(); "Pulling next task for processing");
+        Ok((id, queue, value))
+    }
+
+    /// Wait for the output of a task, returning it serialized.
+    ///
+    /// The output of a completed task is removed from the store once every pull waiting on it has it. Dead tasks are
+    /// kept until they're purged or re-enqueued, so their output can be pulled again.
+    ///
+    /// # Returns
+    ///
+    /// The serialized output, and whether it was removed from the store.
+    ///
+    pub(crate) async fn pull_raw(&self, id: TaskId) -> Result<(Vec<u8>, bool), anyhow::Error> {
+        let (output, removed) = PullTask::new(id, self).await?;
+        trace!(id, bytes = output.len(), removed; "Pulled from store");
+        Ok((output, removed))
+    }
+
+    /// Return a task that was dequeued but never handed over to its queue, as if it had never been dequeued.
+    pub(crate) fn release(&self, id: TaskId) {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(leased) = self.busy.lock().unwrap().remove(id) else {
+            return;
         };
-        let n = *last_count;
 
-        TaskId::from_parts(timestamp, n)
+        debug!(id; "Returning undelivered task to its queue");
+        requeue(&mut queues, id, leased.queued, leased.rank);
+        drop(queues);
+        self.wake_dequeuers();
+    }
+
+    /// Whether a task can still be acknowledged, because it's being processed or its lease expired before it was.
+    pub(crate) fn can_acknowledge(&self, id: TaskId) -> bool {
+        let queues = self.queues.lock().unwrap();
+        self.busy.lock().unwrap().contains(id)
+            || queues.values().any(|queue| queue.iter().any(|enqueued| enqueued.id == id))
+    }
+
+    /// Extend the leases of tasks still being processed, so they aren't returned to their queues while they run.
+    pub(crate) fn renew_leases_raw(&self, ids: &[TaskId]) {
+        let expires_at = Instant::now() + self.lease_timeout;
+        let mut busy = self.busy.lock().unwrap();
+        for id in ids {
+            busy.renew(*id, expires_at);
+        }
+    }
+
+    /// Stop a task from being processed, acknowledging its lease, even if it has already expired.
+    ///
+    /// # Returns
+    ///
+    /// The task, or `None` if it's no longer being processed, e.g. because another attempt already acknowledged it.
+    ///
+    fn acknowledge(&self, id: TaskId) -> Option<(QueuedValue, Rank)> {
+        let (queued, rank) = {
+            let mut queues = self.queues.lock().unwrap();
+            let mut busy = self.busy.lock().unwrap();
+            match busy.remove(id) {
+                Some(leased) => (leased.queued, leased.rank),
+                None => remove_enqueued(&mut queues, id)?,
+            }
+        };
+
+        // The task's concurrency key may have been holding back another task.
+        if rank.concurrency.is_some() {
+            self.wake_dequeuers();
+        }
+        Some((queued, rank))
+    }
+
+    /// Publish the serialized output of a task being processed, acknowledging its lease.
+    ///
+    /// A result published after the task's lease expired is still accepted, and the task isn't processed again. A result
+    /// for a task that's already been acknowledged is ignored.
+    ///
+    pub(crate) fn publish_raw(&self, id: TaskId, output: Vec<u8>) {
+        trace!(id, bytes = output.len(); "Publishing completed task");
+
+        match self.acknowledge(id) {
+            Some((queued, _)) => self.finish(id, queued, output),
+            None => warn!(id; "Ignoring result of task that's no longer being processed"),
+        }
+    }
+
+    /// Cancel a task that hasn't started processing, storing `output` as its result.
+    pub(crate) fn cancel_raw(&self, id: TaskId, output: Vec<u8>) -> Cancellation {
+        let queued = {
+            let mut queues = self.queues.lock().unwrap();
+            let busy = self.busy.lock().unwrap();
+            let mut waiting = self.waiting.lock().unwrap();
+            if busy.contains(id) {
+                return Cancellation::Processing;
+            }
+
+            let Some((queued, _)) = remove_enqueued(&mut queues, id).or_else(|| remove_waiting(&mut waiting, id)) else {
+                return Cancellation::NotFound;
+            };
+            queued
+        };
+
+        trace!(id, bytes = output.len(); "Cancelling task");
+        self.finish(id, queued, output);
+        Cancellation::Cancelled
+    }
+
+    /// Store the output of a task, waking whoever is waiting to pull it.
+    fn finish(&self, id: TaskId, queued: QueuedValue, output: Vec<u8>) {
+        let completed_at = SystemTime::now();
+        let mut completed = self.completed.lock().unwrap();
+        completed.insert(id, CompletedValue { queued, output, completed_at });
+        self.wake_pullers(id);
+    }
+
+    /// Wake every pull waiting on a task's output. Each pull removes its own waker once it has the output.
+    fn wake_pullers(&self, id: TaskId) {
+        let wakers = self.completed_wakers.lock().unwrap();
+        for waker in wakers.get(&id).into_iter().flat_map(|wakers| wakers.values()) {
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Return a task being processed to its queue once `delay` has passed, replacing its serialized value.
+    ///
+    /// Like publishing, this acknowledges the task's lease, even if it has already expired.
+    ///
+    pub(crate) fn retry_raw(&self, id: TaskId, value: Vec<u8>, delay: Duration) -> Result<(), anyhow::Error> {
+        trace!(id, bytes = value.len(), delay:?; "Retrying task");
+
+        match self.acknowledge(id) {
+            Some((queued, _)) => self.defer(id, &queued.queue, value, delay, StoredTaskStatus::Retrying)?,
+            None => warn!(id; "Ignoring retry of task that's no longer being processed"),
+        }
+        Ok(())
+    }
+
+    /// Hold back a task being processed until `delay` has passed, keeping its place in its queue.
+    ///
+    /// Like publishing, this acknowledges the task's lease, even if it has already expired.
+    ///
+    pub(crate) fn postpone_raw(&self, id: TaskId, delay: Duration) {
+        trace!(id, delay:?; "Postponing task");
+
+        let Some((queued, rank)) = self.acknowledge(id) else {
+            warn!(id; "Ignoring postponement of task that's no longer being processed");
+            return;
+        };
+
+        // The task's value is unchanged, so its rank doesn't need decoding again.
+        let available_at = Instant::now() + delay;
+        let waiting = WaitingValue { queued, rank, status: StoredTaskStatus::Enqueued };
+        self.waiting.lock().unwrap().insert((available_at, id), waiting);
+        self.wake_dequeuers();
+    }
+
+    /// Move a task being processed to the dead-letter area, along with the serialized output of its final attempt.
+    pub(crate) fn dead_letter_raw(&self, id: TaskId, output: Vec<u8>) {
+        trace!(id, bytes = output.len(); "Moving failed task to dead-letter area");
+
+        let Some((queued, _)) = self.acknowledge(id) else {
+            warn!(id; "Ignoring failure of task that's no longer being processed");
+            return;
+        };
+        let mut dead = self.dead.lock().unwrap();
+        dead.insert(id, CompletedValue { queued, output, completed_at: SystemTime::now() });
+        self.wake_pullers(id);
+    }
+
+    /// Return dead tasks to their queues with their attempts reset.
+    ///
+    /// # Arguments
+    ///
+    /// `ids` - The IDs of the dead tasks to re-enqueue, or `None` for all of them.
+    ///
+    /// # Returns
+    ///
+    /// The IDs and new values of the re-enqueued tasks.
+    ///
+    pub(crate) fn requeue_dead_raw(&self, ids: Option<Vec<TaskId>>) -> Result<Vec<(TaskId, Task)>, anyhow::Error> {
+        let buried = {
+            let mut dead = self.dead.lock().unwrap();
+            let ids = ids.unwrap_or_else(|| dead.keys().copied().collect());
+            ids.into_iter()
+                .filter_map(|id| dead.remove(&id).map(|buried| (id, buried.queued)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut requeued = vec![];
+        for (id, queued) in buried {
+            let mut task: Task = decode(&queued.value)?;
+            task.attempts = 0;
+            self.enqueue(id, &queued.queue, encode(&task)?)?;
+            requeued.push((id, task));
+        }
+        Ok(requeued)
+    }
+
+    /// Remove dead tasks from the store.
+    ///
+    /// # Arguments
+    ///
+    /// `ids` - The IDs of the dead tasks to remove, or `None` for all of them.
+    ///
+    /// # Returns
+    ///
+    /// The IDs of the removed tasks.
+    ///
+    pub(crate) fn purge_dead_raw(&self, ids: Option<Vec<TaskId>>) -> Vec<TaskId> {
+        let mut dead = self.dead.lock().unwrap();
+        match ids {
+            Some(ids) => ids.into_iter().filter(|id| dead.remove(id).is_some()).collect(),
+            None => dead.drain().map(|(id, _)| id).collect(),
+        }
+    }
+}
+
+impl Default for TasksInMemory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -76,16 +608,21 @@ impl PushStore for TasksInMemory {
         S: AsRef<str> + Send,
         V: Serialize + Send,
     {
-        let value = serde_json::to_string(&value)?;
-        let id = Self::next_id();
-
-        let mut queue = self.queue.lock().await;
-        queue.push((id, value));
-        let mut wakers = self.queue_wakers.lock().await;
-        if let Some(waker) = wakers.pop() {
-            waker.wake();
-        }
+        let value = encode(&value)?;
+        let id = TaskId::generate();
+        self.enqueue(id, queue.as_ref(), value)?;
+        Ok(id)
+    }
 
+    async fn schedule<S, V>(&self, queue: S, value: V, run_at: SystemTime) -> Result<Self::Id, Self::Error>
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let id = TaskId::generate();
+        let delay = run_at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.defer(id, queue.as_ref(), value, delay, StoredTaskStatus::Scheduled)?;
         Ok(id)
     }
 }
@@ -98,10 +635,8 @@ impl PullStore for TasksInMemory {
     where
         D: DeserializeOwned,
     {
-        let output = PullTask::new(id, &self).await?;
-        trace!(id, output:?; "Pulled from store");
-
-        let output = serde_json::from_str(&output)?;
+        let (output, _) = self.pull_raw(id).await?;
+        let output = decode(&output)?;
         Ok(output)
     }
 }
@@ -110,159 +645,1017 @@ impl ProcessStore for TasksInMemory {
     type Id = TaskId;
     type Error = anyhow::Error;
 
-    async fn dequeue<D>(&self) -> Result<(Self::Id, D), Self::Error>
+    async fn dequeue<D>(&self, queues: &[String]) -> Result<(Self::Id, String, D), Self::Error>
     where
         D: DeserializeOwned,
     {
-        let (id, value) = NextTask::new(&self).await?;
-        trace!(id, value:?; "Pulling next task for processing");
-
-        let value = serde_json::from_str(&value)?;
-        Ok((id, value))
+        let (id, queue, value) = self.dequeue_raw(queues).await?;
+        let claimed = Claimed::new(self, id);
+        let value = decode(&value)?;
+        claimed.deliver();
+        Ok((id, queue, value))
     }
 
     async fn publish_result<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
     where
         S: Serialize + Send,
     {
-        let output = serde_json::to_string(&output)?;
-        trace!(id, output:?; "Publishing completed task");
+        let output = encode(&output)?;
+        self.publish_raw(id, output);
+        Ok(())
+    }
 
-        let mut completed = self.completed.lock().await;
-        completed.insert(id, output);
-        let mut wakers = self.completed_wakers.lock().await;
-        if let Some(waker) = wakers.remove(&id) {
-            waker.wake();
-        }
+    async fn retry<V>(&self, id: Self::Id, value: V, delay: Duration) -> Result<(), Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        self.retry_raw(id, value, delay)
+    }
+
+    async fn dead_letter<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        self.dead_letter_raw(id, output);
+        Ok(())
+    }
+
+    async fn renew_leases(&self, ids: &[Self::Id]) -> Result<(), Self::Error> {
+        self.renew_leases_raw(ids);
+        Ok(())
+    }
 
+    async fn postpone(&self, id: Self::Id, delay: Duration) -> Result<(), Self::Error> {
+        self.postpone_raw(id, delay);
         Ok(())
     }
 }
 
+/// A dequeued task that goes back to its queue when dropped, unless it has been handed over to be processed.
+pub(crate) struct Claimed<'a> {
+    tasks: &'a TasksInMemory,
+    id: Option<TaskId>,
+}
+
+impl<'a> Claimed<'a> {
+    pub(crate) fn new(tasks: &'a TasksInMemory, id: TaskId) -> Self {
+        Self { tasks, id: Some(id) }
+    }
+
+    /// Keep the task leased now that it has been handed over.
+    pub(crate) fn deliver(mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for Claimed<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.tasks.release(id);
+        }
+    }
+}
+
 struct PullTask {
     task_id: TaskId,
-    completed_wakers: Arc<Mutex<HashMap<TaskId, Waker>>>,
-    completed: Arc<Mutex<HashMap<TaskId, String>>>,
+    pull_id: u64,
+    completed_wakers: Arc<Mutex<CompletedWakers>>,
+    completed: Arc<Mutex<Completed>>,
+    dead: Arc<Mutex<HashMap<TaskId, CompletedValue>>>,
 }
 
 impl PullTask {
     pub fn new(task_id: TaskId, tasks_in_memory: &TasksInMemory) -> Self {
         Self {
             task_id,
+            pull_id: NEXT_PULL_ID.fetch_add(1, Ordering::Relaxed),
             completed_wakers: tasks_in_memory.completed_wakers.clone(),
             completed: tasks_in_memory.completed.clone(),
+            dead: tasks_in_memory.dead.clone(),
         }
     }
 }
 
 impl Future for PullTask {
-    type Output = Result<String, anyhow::Error>;
+    type Output = Result<(Vec<u8>, bool), anyhow::Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let completed = pin!(self.completed.lock());
-        match completed.poll(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(mut completed) => {
-                if let Some((_, task)) = completed.remove_entry(&self.task_id) {
-                    return Poll::Ready(Ok(task));
-                }
+        // Keep `completed` and `dead` locked while registering the waker so a result published in between isn't missed.
+        let mut completed = self.completed.lock().unwrap();
+        let dead = self.dead.lock().unwrap();
+        let mut wakers = self.completed_wakers.lock().unwrap();
+        let waiting = wakers.entry(self.task_id).or_default();
+
+        let output = if completed.get(self.task_id).is_some() {
+            // Other pulls waiting on the task get the output too, so only the last one removes it.
+            if waiting.keys().all(|pull_id| *pull_id == self.pull_id) {
+                completed.remove(self.task_id).map(|completed| (completed.output, true))
+            } else {
+                completed.get(self.task_id).map(|completed| (completed.output.clone(), false))
             }
+        } else {
+            dead.get(&self.task_id).map(|dead| (dead.output.clone(), false))
         };
 
-        let wakers = pin!(self.completed_wakers.lock());
-        match wakers.poll(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(mut wakers) => {
-                if let Some(waker) = wakers.get_mut(&self.task_id) {
-                    waker.clone_from(cx.waker());
-                } else {
-                    wakers.insert(self.task_id, cx.waker().clone());
-                };
-            }
+        let Some(output) = output else {
+            waiting.insert(self.pull_id, cx.waker().clone());
+            return Poll::Pending;
         };
 
-        Poll::Pending
+        waiting.remove(&self.pull_id);
+        if waiting.is_empty() {
+            wakers.remove(&self.task_id);
+        }
+        Poll::Ready(Ok(output))
+    }
+}
+
+impl Drop for PullTask {
+    fn drop(&mut self) {
+        // A pull that gave up waiting, e.g. because it timed out, no longer needs waking.
+        let mut wakers = self.completed_wakers.lock().unwrap();
+        if let Some(waiting) = wakers.get_mut(&self.task_id) {
+            waiting.remove(&self.pull_id);
+            if waiting.is_empty() {
+                wakers.remove(&self.task_id);
+            }
+        }
     }
 }
 
 struct NextTask {
+    names: Vec<String>,
+    order: QueueOrder,
+    lease_timeout: Duration,
     queue_wakers: Arc<Mutex<Vec<Waker>>>,
-    queue: Arc<Mutex<Vec<(TaskId, String)>>>,
+    queues: Arc<Mutex<Queues>>,
+    busy: Arc<Mutex<Busy>>,
+    waiting: Arc<Mutex<Waiting>>,
+    next_due: Option<Pin<Box<Sleep>>>,
 }
 
 impl NextTask {
-    pub fn new(tasks_in_memory: &TasksInMemory) -> Self {
+    pub fn new(names: &[String], tasks_in_memory: &TasksInMemory) -> Self {
         Self {
+            names: names.to_vec(),
+            order: tasks_in_memory.order,
+            lease_timeout: tasks_in_memory.lease_timeout,
             queue_wakers: tasks_in_memory.queue_wakers.clone(),
-            queue: tasks_in_memory.queue.clone(),
+            queues: tasks_in_memory.queues.clone(),
+            busy: tasks_in_memory.busy.clone(),
+            waiting: tasks_in_memory.waiting.clone(),
+            next_due: None,
         }
     }
 }
 
 impl Future for NextTask {
-    type Output = Result<(TaskId, String), anyhow::Error>;
+    type Output = Result<(TaskId, String, Vec<u8>), anyhow::Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let queue = pin!(self.queue.lock());
-        match queue.poll(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(mut completed) => {
-                if let Some(task) = completed.pop() {
-                    return Poll::Ready(Ok(task));
+        let this = self.get_mut();
+        loop {
+            let now = Instant::now();
+
+            // Keep `queues` locked while registering the waker so a task pushed in between isn't missed.
+            let mut queues = this.queues.lock().unwrap();
+            let mut busy = this.busy.lock().unwrap();
+            let mut waiting = this.waiting.lock().unwrap();
+            requeue_due(&mut queues, &mut busy, &mut waiting, now);
+
+            for name in &this.names {
+                let next = queues.get_mut(name).and_then(|queue| pop_next(queue, this.order, &busy.running));
+                if let Some(Enqueued { rank, id, value }) = next {
+                    let queued = QueuedValue { queue: name.clone(), value: value.clone() };
+                    let expires_at = now + this.lease_timeout;
+                    busy.insert(id, LeasedValue { queued, rank, expires_at });
+                    return Poll::Ready(Ok((id, name.clone(), value)));
                 }
             }
-        };
 
-        let wakers = pin!(self.queue_wakers.lock());
-        match wakers.poll(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(mut wakers) => {
+            // Wakers are drained when woken, so this only registers once per wake-up rather than on every poll.
+            let mut wakers = this.queue_wakers.lock().unwrap();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
                 wakers.push(cx.waker().clone());
             }
-        };
 
-        Poll::Pending
+            // Wake up again when the next task is due back in its queue, since it may belong to one of ours.
+            let availability = waiting.keys().next().map(|(available_at, _)| *available_at);
+            let Some(due_at) = busy.next_expiry().into_iter().chain(availability).min() else {
+                return Poll::Pending;
+            };
+            drop((queues, busy, waiting, wakers));
+
+            let next_due = this.next_due.get_or_insert_with(|| Box::pin(sleep_until(due_at)));
+            next_due.as_mut().reset(due_at);
+            if next_due.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
     }
 }
 
+/// The schedule as of firing its occurrence due at `due_at`, or `None` if that occurrence already fired.
+fn fired(stored: &StoredSchedule, due_at: SystemTime) -> Option<StoredSchedule> {
+    if stored.last_fired() >= due_at {
+        return None;
+    }
+
+    Some(StoredSchedule::new(stored.schedule.clone(), SystemTime::now().max(due_at)))
+}
+
+/// Return tasks whose leases have expired, or that are done waiting, to their queues.
+fn requeue_due(queues: &mut Queues, busy: &mut Busy, waiting: &mut Waiting, now: Instant) {
+    for (id, LeasedValue { queued, rank, .. }) in busy.expire(now) {
+        debug!(id; "Lease expired, returning task to queue");
+        requeue(queues, id, queued, rank);
+    }
+
+    while let Some(entry) = waiting.first_entry() {
+        let (available_at, id) = *entry.key();
+        if available_at > now {
+            break;
+        }
+        let WaitingValue { queued, rank, .. } = entry.remove();
+        requeue(queues, id, queued, rank);
+    }
+}
+
+/// Take the next task from a queue, among the tasks with the highest priority.
+///
+/// Tasks whose concurrency key already has as many tasks being processed as its limit allows are skipped.
+///
+fn pop_next(queue: &mut VecDeque<Enqueued>, order: QueueOrder, running: &HashMap<String, usize>) -> Option<Enqueued> {
+    let runnable = |index: &usize| match &queue[*index].rank.concurrency {
+        Some((key, limit)) => running.get(key).copied().unwrap_or_default() < *limit,
+        None => true,
+    };
+
+    let index = match order {
+        QueueOrder::Fifo => (0..queue.len()).find(runnable),
+        QueueOrder::Lifo => {
+            // Newest first within each priority, from the highest priority down.
+            let mut start = 0;
+            let mut found = None;
+            while found.is_none() && start < queue.len() {
+                let priority = queue[start].rank.priority;
+                let end = start + queue.range(start..).take_while(|other| other.rank.priority == priority).count();
+                found = (start..end).rev().find(runnable);
+                start = end;
+            }
+            found
+        }
+    }?;
+
+    queue.remove(index)
+}
+
+/// Insert a task back into its queue, keeping the queue sorted by priority and ID.
+fn requeue(queues: &mut Queues, id: TaskId, queued: QueuedValue, rank: Rank) {
+    let enqueued = Enqueued { rank, id, value: queued.value };
+    let queue = queues.entry(queued.queue).or_default();
+    let index = queue.partition_point(|other| {
+        (Reverse(other.rank.priority), other.id) < (Reverse(enqueued.rank.priority), enqueued.id)
+    });
+    queue.insert(index, enqueued);
+}
+
+/// Remove a task from whichever queue it's in, returning it if found.
+fn remove_enqueued(queues: &mut Queues, id: TaskId) -> Option<(QueuedValue, Rank)> {
+    queues.iter_mut().find_map(|(name, queue)| {
+        let index = queue.iter().position(|enqueued| enqueued.id == id)?;
+        let Enqueued { rank, value, .. } = queue.remove(index)?;
+        Some((QueuedValue { queue: name.clone(), value }, rank))
+    })
+}
+
+/// Remove a task that's waiting to be returned to its queue, returning it if found.
+fn remove_waiting(waiting: &mut Waiting, id: TaskId) -> Option<(QueuedValue, Rank)> {
+    let key = *waiting.keys().find(|(_, other_id)| *other_id == id)?;
+    waiting.remove(&key).map(|waiting| (waiting.queued, waiting.rank))
+}
+
 impl QueryStore for TasksInMemory {
+    type Id = TaskId;
     type Error = anyhow::Error;
 
     async fn state(&self) -> Result<StoreState, Self::Error> {
-        let mut tasks = vec![];
-        for (task_id, task_str) in self.queue.lock().await.iter() {
-            let task: Task = serde_json::from_str(task_str)?;
-            tasks.push(StoredTask {
-                id: task_id.clone(),
-                status: StoredTaskStatus::Enqueued,
-                definition: task.definition,
-                args: task.args,
-                kwargs: task.kwargs,
-            });
+        let mut state = StoreState::default();
+        for (queue, tasks) in self.queues.lock().unwrap().iter() {
+            for Enqueued { id, value, .. } in tasks {
+                state.insert(stored_task(*id, queue, value, StoredTaskStatus::Enqueued)?);
+            }
         }
 
-        for (task_id, task_str) in self.busy.lock().await.iter() {
-            let task: Task = serde_json::from_str(task_str)?;
-            tasks.push(StoredTask {
-                id: task_id.clone(),
-                status: StoredTaskStatus::Processing,
-                definition: task.definition,
-                args: task.args,
-                kwargs: task.kwargs,
-            });
+        for (task_id, leased) in self.busy.lock().unwrap().leased.iter() {
+            let QueuedValue { queue, value } = &leased.queued;
+            state.insert(stored_task(*task_id, queue, value, StoredTaskStatus::Processing)?);
         }
 
-        for (task_id, task_str) in self.completed.lock().await.iter() {
-            let task: Task = serde_json::from_str(task_str)?;
-            tasks.push(StoredTask {
-                id: task_id.clone(),
-                status: StoredTaskStatus::Completed,
-                definition: task.definition,
-                args: task.args,
-                kwargs: task.kwargs,
+        for ((_, task_id), waiting) in self.waiting.lock().unwrap().iter() {
+            let QueuedValue { queue, value } = &waiting.queued;
+            state.insert(stored_task(*task_id, queue, value, waiting.status.clone())?);
+        }
+
+        for (task_id, completed) in self.completed.lock().unwrap().outputs.iter() {
+            state.insert(finished_task(*task_id, completed, StoredTaskStatus::Completed)?);
+        }
+
+        for (task_id, dead) in self.dead.lock().unwrap().iter() {
+            state.insert(finished_task(*task_id, dead, StoredTaskStatus::Failed)?);
+        }
+
+        Ok(state)
+    }
+
+    async fn enqueued(&self, queues: &[String]) -> Result<usize, Self::Error> {
+        let queued = {
+            let enqueued = self.queues.lock().unwrap();
+            queues.iter().filter_map(|queue| enqueued.get(queue)).map(VecDeque::len).sum::<usize>()
+        };
+        let postponed = self.waiting
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|waiting| waiting.status == StoredTaskStatus::Enqueued && queues.contains(&waiting.queued.queue))
+            .count();
+        Ok(queued + postponed)
+    }
+
+    async fn task(&self, id: Self::Id) -> Result<Option<StoredTask>, Self::Error> {
+        {
+            let queues = self.queues.lock().unwrap();
+            let busy = self.busy.lock().unwrap();
+            let waiting = self.waiting.lock().unwrap();
+
+            let enqueued = queues.iter().find_map(|(queue, tasks)| {
+                let Enqueued { value, .. } = tasks.iter().find(|enqueued| enqueued.id == id)?;
+                Some((queue, value))
             });
+            if let Some((queue, value)) = enqueued {
+                return Ok(Some(stored_task(id, queue, value, StoredTaskStatus::Enqueued)?));
+            }
+
+            if let Some(LeasedValue { queued, .. }) = busy.get(id) {
+                return Ok(Some(stored_task(id, &queued.queue, &queued.value, StoredTaskStatus::Processing)?));
+            }
+
+            let waiting = waiting.iter().find(|((_, other_id), _)| *other_id == id);
+            if let Some((_, WaitingValue { queued, status, .. })) = waiting {
+                return Ok(Some(stored_task(id, &queued.queue, &queued.value, status.clone())?));
+            }
+        }
+
+        let completed = self.completed.lock().unwrap().get(id).cloned();
+        let finished = match completed {
+            Some(completed) => Some((completed, StoredTaskStatus::Completed)),
+            None => self.dead.lock().unwrap().get(&id).cloned().map(|dead| (dead, StoredTaskStatus::Failed)),
+        };
+        let Some((finished, status)) = finished else {
+            return Ok(None);
+        };
+
+        let mut task = finished_task(id, &finished, status)?;
+        task.result = Some(decode(&finished.output)?);
+        Ok(Some(task))
+    }
+
+    async fn dead_tasks(&self) -> Result<Vec<DeadTask>, Self::Error> {
+        let mut dead_tasks = vec![];
+        for (task_id, dead) in self.dead.lock().unwrap().iter() {
+            let task = finished_task(*task_id, dead, StoredTaskStatus::Failed)?;
+            let failure = decode(&dead.output)?;
+            dead_tasks.push(DeadTask { task, failure });
+        }
+
+        dead_tasks.sort_by_key(|dead| dead.task.id);
+        Ok(dead_tasks)
+    }
+
+    async fn requeue_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        Ok(self.requeue_dead_raw(ids)?.len())
+    }
+
+    async fn purge_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        Ok(self.purge_dead_raw(ids).len())
+    }
+
+    async fn cancel<S>(&self, id: Self::Id, output: S) -> Result<Cancellation, Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        Ok(self.cancel_raw(id, output))
+    }
+}
+
+impl ScheduleStore for TasksInMemory {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn schedules(&self) -> Result<Vec<StoredSchedule>, Self::Error> {
+        Ok(self.schedules.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn save_schedule(&self, schedule: RecurringSchedule) -> Result<StoredSchedule, Self::Error> {
+        Ok(self.save_schedule_raw(schedule))
+    }
+
+    async fn remove_schedule(&self, name: &str) -> Result<bool, Self::Error> {
+        Ok(self.remove_schedule_raw(name))
+    }
+
+    async fn fire_schedule<V>(&self, name: &str, due_at: SystemTime, value: V) -> Result<Option<Self::Id>, Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let Some(stored) = self.claim_schedule(name, due_at) else {
+            return Ok(None);
+        };
+
+        let id = TaskId::generate();
+        self.enqueue(id, &stored.schedule.queue, value)?;
+        Ok(Some(id))
+    }
+}
+
+fn stored_task(
+    id: TaskId,
+    queue: &str,
+    value: &[u8],
+    status: StoredTaskStatus,
+) -> Result<StoredTask, anyhow::Error> {
+    let task: Task = decode(value)?;
+    Ok(StoredTask {
+        id,
+        queue: queue.to_string(),
+        status,
+        priority: task.priority,
+        definition: task.definition,
+        args: task.args,
+        kwargs: task.kwargs,
+        attempts: task.attempts,
+        created_at: millis(id.created_at()),
+        finished_at: None,
+        result: None,
+    })
+}
+
+fn finished_task(id: TaskId, finished: &CompletedValue, status: StoredTaskStatus) -> Result<StoredTask, anyhow::Error> {
+    let QueuedValue { queue, value } = &finished.queued;
+    let mut task = stored_task(id, queue, value, status)?;
+    task.finished_at = Some(millis(finished.completed_at));
+    Ok(task)
+}
+
+/// Serialize a task value or output the way it's kept in memory.
+///
+/// MessagePack keeps already serialized task arguments as raw bytes rather than escaping them into a string.
+///
+pub(crate) fn encode<T: Serialize + ?Sized>(object: &T) -> Result<Vec<u8>, anyhow::Error> {
+    io::encode(Encoding::MessagePack, object)
+}
+
+/// Deserialize a task value or output kept in memory.
+pub(crate) fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, anyhow::Error> {
+    io::decode(Encoding::MessagePack, bytes)
+}
+
+/// Milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::TaskResult;
+    use futures::FutureExt;
+
+
+    fn queues(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_only_from_requested_queues() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        store.push("billing", Task::for_test("Charge")).await?;
+        let email_id = store.push("email", Task::for_test("SendEmail")).await?;
+
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues(&["email"])).await?;
+        assert_eq!(id, email_id);
+        assert_eq!(value, Task::for_test("SendEmail"));
+
+        let next = store.dequeue::<Task>(&queues(&["email"])).now_or_never();
+        assert!(next.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_prefers_queues_in_order() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        store.push("low", Task::for_test("Low")).await?;
+        store.push("critical", Task::for_test("Critical")).await?;
+
+        let (_, first_queue, first): (TaskId, String, Task) = store.dequeue(&queues(&["critical", "low"])).await?;
+        let (_, second_queue, second): (TaskId, String, Task) = store.dequeue(&queues(&["critical", "low"])).await?;
+        assert_eq!((first_queue.as_str(), first), ("critical", Task::for_test("Critical")));
+        assert_eq!((second_queue.as_str(), second), ("low", Task::for_test("Low")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_higher_priority_tasks_first() -> Result<(), anyhow::Error> {
+        for (order, expected) in [
+            (QueueOrder::Fifo, ["Urgent", "Reset", "Newsletter", "Digest", "Cleanup"]),
+            (QueueOrder::Lifo, ["Reset", "Urgent", "Digest", "Newsletter", "Cleanup"]),
+        ] {
+            let store = TasksInMemory::with_order(order);
+            for (definition, priority) in [("Newsletter", 0), ("Urgent", 10), ("Cleanup", -5), ("Digest", 0), ("Reset", 10)] {
+                store.push("default", Task { priority, ..Task::for_test(definition) }).await?;
+            }
+
+            let mut dequeued = vec![];
+            for _ in 0..expected.len() {
+                let (_, _, value) = store.dequeue::<Task>(&queues(&["default"])).await?;
+                dequeued.push(value.definition);
+            }
+            assert_eq!(dequeued, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_holds_back_tasks_whose_concurrency_key_is_at_its_limit() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let keyed = |definition: &str, key: &str, limit: Option<u32>| Task {
+            concurrency_key: Some(key.to_string()),
+            concurrency_limit: limit,
+            ..Task::for_test(definition)
+        };
+        let first_id = store.push("default", keyed("First", "account:1", None)).await?;
+        store.push("default", keyed("Second", "account:1", None)).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+
+        let queues = vec!["default".to_string()];
+        let mut dequeued = vec![];
+        for _ in 0..3 {
+            let (_, _, value) = store.dequeue::<Task>(&queues).await?;
+            dequeued.push(value.definition);
+        }
+        assert_eq!(dequeued, ["First", "Report", "Report"]);
+        assert!(store.dequeue::<Task>(&queues).now_or_never().is_none());
+
+        store.publish_result(first_id, TaskResult::Success { result: "6".to_string() }).await?;
+        let (_, _, value) = store.dequeue::<Task>(&queues).await?;
+        assert_eq!(value.definition, "Second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_rejects_values_that_arent_tasks() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        assert!(store.push("default", "not a task").await.is_err());
+        assert!(store.dequeue::<Task>(&queues(&["default"])).now_or_never().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_waits_for_push_to_requested_queue() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let names = queues(&["email"]);
+        let dequeue = store.dequeue::<Task>(&names);
+
+        let (result, _) = tokio::join!(dequeue, async {
+            store.push("billing", Task::for_test("Charge")).await?;
+            store.push("email", Task::for_test("SendEmail")).await
+        });
+        let (_, _, value) = result?;
+        assert_eq!(value, Task::for_test("SendEmail"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_oldest_task_first_by_default() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let mut pushed = vec![];
+        for _ in 0..5000 {
+            pushed.push(store.push("default", Task::for_test("Task")).await?);
+        }
+
+        let names = queues(&["default"]);
+        let mut dequeued = vec![];
+        for _ in 0..5000 {
+            let (id, _, _) = store.dequeue::<Task>(&names).await?;
+            dequeued.push(id);
+        }
+
+        assert_eq!(dequeued, pushed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_newest_task_first_when_lifo() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::with_order(QueueOrder::Lifo);
+        let mut pushed = vec![];
+        for _ in 0..5000 {
+            pushed.push(store.push("default", Task::for_test("Task")).await?);
+        }
+
+        let names = queues(&["default"]);
+        let mut dequeued = vec![];
+        for _ in 0..5000 {
+            let (id, _, _) = store.dequeue::<Task>(&names).await?;
+            dequeued.push(id);
+        }
+
+        pushed.reverse();
+        assert_eq!(dequeued, pushed);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_dequeuers_each_see_tasks_in_fifo_order() -> Result<(), anyhow::Error> {
+        const TASK_COUNT: usize = 5000;
+        const DEQUEUER_COUNT: usize = 8;
+
+        let store = TasksInMemory::new();
+        let claimed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let pusher = tokio::spawn({
+            let store = store.clone();
+            async move {
+                let mut pushed = vec![];
+                for _ in 0..TASK_COUNT {
+                    pushed.push(store.push("default", Task::for_test("Task")).await?);
+                }
+                Result::<_, anyhow::Error>::Ok(pushed)
+            }
+        });
+
+        let dequeuers = (0..DEQUEUER_COUNT)
+            .map(|_| {
+                let store = store.clone();
+                let claimed = claimed.clone();
+                tokio::spawn(async move {
+                    let names = queues(&["default"]);
+                    let mut dequeued = vec![];
+                    while claimed.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < TASK_COUNT {
+                        let (id, _, _) = store.dequeue::<Task>(&names).await?;
+                        dequeued.push(id);
+                    }
+                    Result::<_, anyhow::Error>::Ok(dequeued)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let pushed = pusher.await??;
+        let mut all_dequeued = vec![];
+        for dequeuer in dequeuers {
+            let dequeued = dequeuer.await??;
+            assert!(dequeued.windows(2).all(|ids| ids[0] < ids[1]));
+            all_dequeued.extend(dequeued);
         }
 
-        Ok(StoreState { tasks })
+        all_dequeued.sort();
+        assert_eq!(all_dequeued, pushed);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dequeue_returns_task_again_when_lease_expires() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new().with_lease_timeout(Duration::from_secs(30));
+        let task_id = store.push("default", Task::for_test("Slow")).await?;
+        let names = queues(&["default"]);
+        store.dequeue::<Task>(&names).await?;
+
+        let started = Instant::now();
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!((id, value), (task_id, Task::for_test("Slow")));
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publish_result_acknowledges_lease() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new().with_lease_timeout(Duration::from_secs(30));
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        let names = queues(&["default"]);
+        store.dequeue::<Task>(&names).await?;
+        store.publish_result(task_id, "6").await?;
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_none());
+        assert_eq!(store.pull::<String>(task_id).await?, "6");
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publish_result_accepts_late_acknowledgement() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new().with_lease_timeout(Duration::from_secs(30));
+        let task_id = store.push("default", Task::for_test("Slow")).await?;
+        let names = queues(&["default"]);
+        store.dequeue::<Task>(&names).await?;
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_some());
+        store.publish_result(task_id, "6").await?;
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_none());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn renewed_lease_keeps_task_from_being_dequeued_again() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new().with_lease_timeout(Duration::from_secs(30));
+        let task_id = store.push("default", Task::for_test("Slow")).await?;
+        let names = queues(&["default"]);
+        store.dequeue::<Task>(&names).await?;
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        store.renew_leases(&[task_id]).await?;
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_none());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publish_result_ignores_duplicate_acknowledgement() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        store.dequeue::<Task>(&queues(&["default"])).await?;
+        store.publish_result(task_id, "6").await?;
+
+        store.publish_result(task_id, "7").await?;
+        store.dead_letter(task_id, "failed").await?;
+        assert_eq!(store.pull::<String>(task_id).await?, "6");
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_returns_task_to_queue_after_delay() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Flaky")).await?;
+        let names = queues(&["default"]);
+        let (_, _, mut value): (TaskId, String, Task) = store.dequeue(&names).await?;
+
+        value.attempts += 1;
+        store.retry(task_id, value.clone(), Duration::from_secs(10)).await?;
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Retrying);
+
+        let started = Instant::now();
+        let (id, _, retried): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!((id, retried), (task_id, value));
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn postponed_task_stays_enqueued_in_its_place() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let first_id = store.push("default", Task { priority: 5, ..Task::for_test("First") }).await?;
+        let names = queues(&["default"]);
+        store.dequeue::<Task>(&names).await?;
+
+        store.postpone(first_id, Duration::from_secs(10)).await?;
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Enqueued);
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_none());
+
+        store.push("default", Task::for_test("Second")).await?;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!((id, value), (first_id, Task { priority: 5, ..Task::for_test("First") }));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_tasks_are_dequeued_once_due() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let run_at = SystemTime::now() + Duration::from_secs(60);
+        let later_id = store.schedule("default", Task::for_test("Later"), run_at).await?;
+        let run_at = SystemTime::now() + Duration::from_secs(30);
+        let sooner_id = store.schedule("default", Task::for_test("Sooner"), run_at).await?;
+        let names = queues(&["default"]);
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Scheduled);
+        assert!(store.dequeue::<Task>(&names).now_or_never().is_none());
+
+        let started = Instant::now();
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!(id, sooner_id);
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!(id, later_id);
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        Ok(())
+    }
+
+    fn failure(message: &str) -> TaskResult {
+        TaskResult::Failure { class: "RuntimeError".to_string(), message: message.to_string(), backtrace: vec![] }
+    }
+
+    #[tokio::test]
+    async fn dead_letter_keeps_task_and_failure_until_purged() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Broken")).await?;
+        store.dequeue::<Task>(&queues(&["default"])).await?;
+        store.dead_letter(task_id, failure("oops")).await?;
+
+        assert_eq!(store.pull::<TaskResult>(task_id).await?, failure("oops"));
+        let dead_tasks = store.dead_tasks().await?;
+        assert_eq!(dead_tasks.len(), 1);
+        assert_eq!(dead_tasks[0].task.id, task_id);
+        assert_eq!(dead_tasks[0].task.status, StoredTaskStatus::Failed);
+        assert_eq!(dead_tasks[0].failure, failure("oops"));
+
+        assert_eq!(store.purge_dead(Some(vec![task_id])).await?, 1);
+        assert!(store.dead_tasks().await?.is_empty());
+        assert!(store.state().await?.queues.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requeue_dead_resets_attempts() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Broken")).await?;
+        let names = queues(&["default"]);
+        let (_, _, mut value): (TaskId, String, Task) = store.dequeue(&names).await?;
+        value.attempts = 2;
+        store.retry(task_id, value, Duration::ZERO).await?;
+        store.dequeue::<Task>(&names).await?;
+        store.dead_letter(task_id, failure("oops")).await?;
+
+        assert_eq!(store.requeue_dead(None).await?, 1);
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&names).await?;
+        assert_eq!((id, value), (task_id, Task::for_test("Broken")));
+        assert!(store.dead_tasks().await?.is_empty());
+        Ok(())
+    }
+
+    fn schedule(name: &str) -> RecurringSchedule {
+        RecurringSchedule {
+            name: name.to_string(),
+            cron: "* * * * *".to_string(),
+            queue: "default".to_string(),
+            definition: "Report".to_string(),
+            args: "[]".into(),
+            kwargs: "{}".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fire_schedule_submits_each_occurrence_once() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let added = store.save_schedule(schedule("report")).await?;
+        let due_at = added.last_fired() + Duration::from_secs(60);
+
+        assert!(store.fire_schedule("report", added.last_fired(), Task::for_test("Report")).await?.is_none());
+        let task_id = store.fire_schedule("report", due_at, Task::for_test("Report")).await?;
+        assert!(task_id.is_some());
+        assert!(store.fire_schedule("report", due_at, Task::for_test("Report")).await?.is_none());
+        assert!(store.fire_schedule("unknown", due_at, Task::for_test("Report")).await?.is_none());
+
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&queues(&["default"])).await?;
+        assert_eq!(Some(id), task_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_schedule_keeps_when_replaced_schedule_last_fired() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let added = store.save_schedule(schedule("report")).await?;
+        let due_at = added.last_fired() + Duration::from_secs(60);
+        store.fire_schedule("report", due_at, Task::for_test("Report")).await?;
+
+        let mut replacement = schedule("report");
+        replacement.cron = "0 * * * *".to_string();
+        let replaced = store.save_schedule(replacement.clone()).await?;
+        assert_eq!(replaced.schedule, replacement);
+        assert!(replaced.last_fired() >= due_at);
+
+        assert!(store.remove_schedule("report").await?);
+        assert!(store.schedules().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_is_looked_up_without_taking_its_result() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Report")).await?;
+        let found = store.task(task_id).await?.expect("task should be stored");
+        assert_eq!(found.status, StoredTaskStatus::Enqueued);
+        assert_eq!(found.attempts, 0);
+        assert!(found.result.is_none());
+
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        assert_eq!(store.task(task_id).await?.unwrap().status, StoredTaskStatus::Processing);
+
+        let success = TaskResult::Success { result: "6".to_string() };
+        store.publish_result(task_id, success.clone()).await?;
+        let found = store.task(task_id).await?.unwrap();
+        assert_eq!(found.status, StoredTaskStatus::Completed);
+        assert!(found.finished_at.is_some());
+        assert_eq!(found.result, Some(success.clone()));
+
+        // Looking the task up doesn't take its result.
+        let pulled: TaskResult = store.pull(task_id).await?;
+        assert_eq!(pulled, success);
+        assert!(store.task(task_id).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_pull_waiting_on_a_task_gets_its_result() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let task_id = store.push("default", Task::for_test("Report")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+
+        // A pull that gives up waiting leaves nothing behind.
+        let timed_out = tokio::time::timeout(Duration::from_millis(10), store.pull::<TaskResult>(task_id)).await;
+        assert!(timed_out.is_err());
+        assert!(store.completed_wakers.lock().unwrap().is_empty());
+
+        let success = TaskResult::Success { result: "6".to_string() };
+        let (first, second, _) = tokio::join!(
+            store.pull::<TaskResult>(task_id),
+            store.pull::<TaskResult>(task_id),
+            store.publish_result(task_id, success.clone()),
+        );
+        assert_eq!(first?, success);
+        assert_eq!(second?, success);
+        assert!(store.task(task_id).await?.is_none());
+        assert!(store.completed_wakers.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_removes_tasks_that_havent_started() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let processing_id = store.push("default", Task::for_test("Processing")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        let enqueued_id = store.push("default", Task::for_test("Enqueued")).await?;
+        let run_at = SystemTime::now() + Duration::from_secs(60);
+        let scheduled_id = store.schedule("default", Task::for_test("Scheduled"), run_at).await?;
+
+        assert_eq!(store.cancel(enqueued_id, TaskResult::Cancelled).await?, Cancellation::Cancelled);
+        assert_eq!(store.cancel(scheduled_id, TaskResult::Cancelled).await?, Cancellation::Cancelled);
+        assert_eq!(store.cancel(processing_id, TaskResult::Cancelled).await?, Cancellation::Processing);
+        assert_eq!(store.cancel(enqueued_id, TaskResult::Cancelled).await?, Cancellation::NotFound);
+
+        let result: TaskResult = store.pull(scheduled_id).await?;
+        assert_eq!(result, TaskResult::Cancelled);
+        let next = store.dequeue::<Task>(&["default".to_string()]).now_or_never();
+        assert!(next.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn state_groups_tasks_by_queue() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let charge_id = store.push("billing", Task::for_test("Charge")).await?;
+        let email_id = store.push("email", Task::for_test("SendEmail")).await?;
+        store.dequeue::<Task>(&queues(&["email"])).await?;
+
+        let state = store.state().await?;
+        assert_eq!(state.queues.len(), 2);
+        assert_eq!(state.queues["billing"][0].id, charge_id);
+        assert_eq!(state.queues["billing"][0].status, StoredTaskStatus::Enqueued);
+        assert_eq!(state.queues["email"][0].id, email_id);
+        assert_eq!(state.queues["email"][0].status, StoredTaskStatus::Processing);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enqueued_counts_tasks_waiting_in_the_given_queues() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        store.push("billing", Task::for_test("Charge")).await?;
+        store.push("billing", Task::for_test("Refund")).await?;
+        store.push("email", Task::for_test("SendEmail")).await?;
+        store.push("reports", Task::for_test("Export")).await?;
+        let (postponed_id, _, _): (TaskId, String, Task) = store.dequeue(&queues(&["billing"])).await?;
+        store.dequeue::<Task>(&queues(&["email"])).await?;
+        store.postpone(postponed_id, Duration::from_secs(60)).await?;
+
+        assert_eq!(store.enqueued(&queues(&["billing", "email"])).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_args_are_kept_without_escaping() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let args = r#"["a \"quoted\" string"]"#;
+        let task_id = store.push("default", Task { args: args.into(), ..Task::for_test("Quote") }).await?;
+
+        let Enqueued { value, .. } = store.queues.lock().unwrap()["default"][0].clone();
+        assert!(value.windows(args.len()).any(|window| window == args.as_bytes()));
+
+        let (id, _, dequeued): (TaskId, String, Task) = store.dequeue(&queues(&["default"])).await?;
+        assert_eq!(id, task_id);
+        assert_eq!(dequeued.args.as_str()?, args);
+        Ok(())
     }
 }
@@ -0,0 +1,1265 @@
+//!
+//! A durable implementation of storing tasks, backed by an embedded SQLite database.
+//!
+//! Tasks and their results are kept in ordinary tables so they can be inspected with SQL:
+//!
+//! * `tasks` - One row per stored task, with its ID, queue, status, priority, concurrency key and limit, serialized
+//!   value, lease expiry, when it's available to retry, and timestamps.
+//! * `task_results` - One row per processed task whose result hasn't been pulled yet, with its serialized output. Dead
+//!   tasks keep their row, holding the result of their final attempt, until they're re-enqueued or purged.
+//! * `schedules` - One row per recurring schedule, with when it last fired.
+//!
+//! Task values and outputs are kept as MessagePack BLOBs, serialized the same way as in `TasksInMemory`. The columns
+//! that decide dequeue order are read from the value when the task is stored.
+//!
+//! Tasks that were being processed when the application stopped are enqueued again when the store is opened. If a
+//! retention period is given, results that aren't pulled within that period are removed.
+//!
+//! Dequeued tasks are leased until their result is published. Tasks whose lease expires are enqueued again, as are
+//! scheduled and retried tasks once they're due. Tasks with a concurrency key stay enqueued while as many tasks with
+//! the same key as its limit allows are being processed.
+//!
+
+use crate::messaging::{RecurringSchedule, Task, TaskId};
+use crate::store::store_state::{DeadTask, StoreState, StoredSchedule, StoredTask, StoredTaskStatus};
+use crate::store::in_memory::{decode, encode, Ranked};
+use crate::store::{
+    Cancellation, ProcessStore, PullStore, PushStore, QueryStore, QueueOrder, ScheduleStore, DEFAULT_LEASE_TIMEOUT,
+};
+use anyhow::{anyhow, Context};
+use log::{debug, trace, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio::task::spawn_blocking;
+
+const SCHEMA: &str = r#"
+    PRAGMA journal_mode = WAL;
+    PRAGMA foreign_keys = ON;
+
+    CREATE TABLE IF NOT EXISTS tasks (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL UNIQUE,
+        queue TEXT NOT NULL,
+        status TEXT NOT NULL,
+        priority INTEGER NOT NULL DEFAULT 0,
+        concurrency_key TEXT,
+        concurrency_limit INTEGER NOT NULL DEFAULT 1,
+        value BLOB NOT NULL,
+        lease_expires_at INTEGER,
+        available_at INTEGER,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS tasks_by_queue_and_status ON tasks (queue, status, priority, seq);
+    CREATE INDEX IF NOT EXISTS tasks_by_concurrency_key ON tasks (concurrency_key, status);
+
+    CREATE TABLE IF NOT EXISTS task_results (
+        task_id TEXT PRIMARY KEY REFERENCES tasks (id) ON DELETE CASCADE,
+        output BLOB NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS schedules (
+        name TEXT PRIMARY KEY,
+        cron TEXT NOT NULL,
+        queue TEXT NOT NULL,
+        definition TEXT NOT NULL,
+        args TEXT NOT NULL,
+        kwargs TEXT NOT NULL,
+        last_fired_at INTEGER NOT NULL
+    );
+"#;
+
+/// Selects the columns read by `stored_task`, joining each task with its result if it has one.
+const SELECT_STORED_TASKS: &str = r#"
+    SELECT t.id, t.queue, t.status, t.value, t.created_at, r.output, r.created_at
+    FROM tasks t LEFT JOIN task_results r ON r.task_id = t.id
+"#;
+
+const ENQUEUED: &str = "enqueued";
+const PROCESSING: &str = "processing";
+const COMPLETED: &str = "completed";
+const RETRYING: &str = "retrying";
+const FAILED: &str = "failed";
+const SCHEDULED: &str = "scheduled";
+
+#[derive(Debug, Clone)]
+pub struct TasksInSqlite {
+    order: QueueOrder,
+    retention: Option<Duration>,
+    lease_timeout: Duration,
+    conn: Arc<Mutex<Connection>>,
+    pushed: Arc<Notify>,
+    published: Arc<Notify>,
+}
+
+impl TasksInSqlite {
+    /// Open a SQLite-backed store, creating the database at `path` if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - The path of the SQLite database file.
+    /// `order` - The order tasks are pulled from each queue.
+    /// `retention` - How long to keep results that haven't been pulled, or `None` to keep them forever.
+    ///
+    /// Opening creates the schema and updates tasks interrupted by the last run, so async callers should open the store
+    /// on a blocking thread.
+    ///
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        order: QueueOrder,
+        retention: Option<Duration>,
+    ) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let conn = Connection::open(path).with_context(|| format!("opening task database {}", path.display()))?;
+        conn.execute_batch(SCHEMA).context("creating task database schema")?;
+        conn.execute(
+            "UPDATE tasks SET status = ?1, lease_expires_at = NULL, updated_at = ?2 WHERE status = ?3",
+            params![ENQUEUED, now(), PROCESSING],
+        )
+        .context("re-enqueueing interrupted tasks")?;
+        expire_results(&conn, retention).context("expiring task results")?;
+
+        Ok(Self {
+            order,
+            retention,
+            lease_timeout: DEFAULT_LEASE_TIMEOUT,
+            conn: Arc::new(Mutex::new(conn)),
+            pushed: Arc::new(Notify::new()),
+            published: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Set how long a dequeued task can go without its result being published before it's enqueued again.
+    pub fn with_lease_timeout(mut self, lease_timeout: Duration) -> Self {
+        self.lease_timeout = lease_timeout;
+        self
+    }
+
+    /// Run `query` with the database connection on a blocking thread, so the runtime isn't held up by the database.
+    async fn with_conn<T, F>(&self, query: F) -> Result<T, anyhow::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, anyhow::Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        spawn_blocking(move || query(&mut conn.lock().unwrap()))
+            .await
+            .context("querying task database")?
+    }
+
+    /// Mark the next enqueued task from one of the given queues as processing, returning it and its queue if there is
+    /// one.
+    ///
+    /// Tasks whose lease has expired, or that are scheduled or retried and now due, are enqueued first.
+    ///
+    async fn try_dequeue(&self, queues: &[String]) -> Result<Option<(TaskId, String, Vec<u8>)>, anyhow::Error> {
+        // Skip tasks whose concurrency key already has as many tasks being processed as its limit allows.
+        let select = r#"
+            SELECT seq, id, value FROM tasks t
+            WHERE queue = ?1 AND status = ?2 AND available_at IS NULL AND (
+                concurrency_key IS NULL
+                OR concurrency_limit > (
+                    SELECT COUNT(*) FROM tasks p WHERE p.concurrency_key = t.concurrency_key AND p.status = ?3
+                )
+            )
+        "#;
+        let sql = match self.order {
+            QueueOrder::Fifo => format!("{select} ORDER BY priority DESC, seq ASC LIMIT 1"),
+            QueueOrder::Lifo => format!("{select} ORDER BY priority DESC, seq DESC LIMIT 1"),
+        };
+
+        let queues = queues.to_vec();
+        let lease_timeout = self.lease_timeout;
+        self.with_conn(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+            let expired = tx.execute(
+                "UPDATE tasks SET status = ?1, lease_expires_at = NULL, updated_at = ?2 WHERE status = ?3 AND lease_expires_at <= ?2",
+                params![ENQUEUED, now, PROCESSING],
+            )?;
+            if expired > 0 {
+                debug!(expired; "Leases expired, returning tasks to queues");
+            }
+            tx.execute(
+                "UPDATE tasks SET status = ?1, available_at = NULL, updated_at = ?2 WHERE status IN (?1, ?3, ?4) AND available_at <= ?2",
+                params![ENQUEUED, now, SCHEDULED, RETRYING],
+            )?;
+
+            for queue in queues {
+                let next = tx
+                    .query_row(&sql, params![queue, ENQUEUED, PROCESSING], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?))
+                    })
+                    .optional()?;
+
+                if let Some((seq, id, value)) = next {
+                    tx.execute(
+                        "UPDATE tasks SET status = ?1, lease_expires_at = ?2, updated_at = ?3 WHERE seq = ?4",
+                        params![PROCESSING, now + lease_timeout.as_millis() as i64, now, seq],
+                    )?;
+                    tx.commit()?;
+                    return Ok(Some((TaskId::try_from(id)?, queue, value)));
+                }
+            }
+
+            tx.commit()?;
+            Ok(None)
+        })
+        .await
+    }
+
+    /// When the next task being processed, postponed, scheduled or retried is due in its queue, in milliseconds since
+    /// the Unix epoch.
+    async fn next_due(&self) -> Result<Option<i64>, anyhow::Error> {
+        self.with_conn(|conn| {
+            let due_at = conn.query_row(
+                "SELECT MIN(COALESCE(lease_expires_at, available_at)) FROM tasks WHERE status IN (?1, ?2, ?3, ?4)",
+                params![PROCESSING, ENQUEUED, SCHEDULED, RETRYING],
+                |row| row.get::<_, Option<i64>>(0),
+            )?;
+            Ok(due_at)
+        })
+        .await
+    }
+
+    /// Remove a task and its result from the store, returning the result if the task has been processed.
+    ///
+    /// Dead tasks are kept until they're re-enqueued or purged.
+    ///
+    async fn try_pull(&self, id: TaskId) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let id = String::from(&id);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let result = tx
+                .query_row(
+                    "SELECT r.output, t.status FROM task_results r JOIN tasks t ON t.id = r.task_id WHERE r.task_id = ?1",
+                    params![id],
+                    |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?)),
+                )
+                .optional()?;
+
+            let Some((output, status)) = result else {
+                return Ok(None);
+            };
+            if status != FAILED {
+                tx.execute("DELETE FROM tasks WHERE id = ?1", params![id])?;
+                tx.commit()?;
+            }
+            Ok(Some(output))
+        })
+        .await
+    }
+
+    /// The IDs of the given dead tasks, or of all dead tasks if `None`.
+    fn dead_ids(conn: &Connection, ids: Option<Vec<TaskId>>) -> Result<Vec<String>, anyhow::Error> {
+        match ids {
+            Some(ids) => Ok(ids.iter().map(String::from).collect()),
+            None => {
+                let mut stmt = conn.prepare("SELECT id FROM tasks WHERE status = ?1 ORDER BY seq")?;
+                let ids = stmt.query_map(params![FAILED], |row| row.get::<_, String>(0))?;
+                Ok(ids.collect::<Result<_, _>>()?)
+            }
+        }
+    }
+}
+
+impl PushStore for TasksInSqlite {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn push<S, V>(&self, queue: S, value: V) -> Result<Self::Id, Self::Error>
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let (priority, concurrency_key, concurrency_limit) = rank(&value)?;
+        let id = TaskId::generate();
+        let queue = queue.as_ref().to_string();
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                r#"
+                INSERT INTO tasks (id, queue, status, priority, concurrency_key, concurrency_limit, value, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)
+                "#,
+                params![String::from(&id), queue, ENQUEUED, priority, concurrency_key, concurrency_limit, value, now()],
+            )?;
+            Ok(())
+        })
+        .await?;
+        self.pushed.notify_waiters();
+
+        Ok(id)
+    }
+
+    async fn schedule<S, V>(&self, queue: S, value: V, run_at: SystemTime) -> Result<Self::Id, Self::Error>
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let (priority, concurrency_key, concurrency_limit) = rank(&value)?;
+        let id = TaskId::generate();
+        let queue = queue.as_ref().to_string();
+        let run_at = run_at.duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                r#"
+                INSERT INTO tasks (id, queue, status, priority, concurrency_key, concurrency_limit, value, available_at, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+                "#,
+                params![
+                    String::from(&id),
+                    queue,
+                    SCHEDULED,
+                    priority,
+                    concurrency_key,
+                    concurrency_limit,
+                    value,
+                    run_at,
+                    now()
+                ],
+            )?;
+            Ok(())
+        })
+        .await?;
+
+        // Dequeuers need to know when to check again.
+        self.pushed.notify_waiters();
+
+        Ok(id)
+    }
+}
+
+impl PullStore for TasksInSqlite {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn pull<D>(&self, id: Self::Id) -> Result<D, Self::Error>
+    where
+        D: DeserializeOwned,
+    {
+        loop {
+            // Register interest before checking so a result published in between isn't missed.
+            let mut published = pin!(self.published.notified());
+            published.as_mut().enable();
+
+            if let Some(output) = self.try_pull(id).await? {
+                trace!(id, bytes = output.len(); "Pulled from store");
+                let output = decode(&output)?;
+                return Ok(output);
+            }
+
+            published.await;
+        }
+    }
+}
+
+impl ProcessStore for TasksInSqlite {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn dequeue<D>(&self, queues: &[String]) -> Result<(Self::Id, String, D), Self::Error>
+    where
+        D: DeserializeOwned,
+    {
+        loop {
+            // Register interest before checking so a task pushed in between isn't missed.
+            let mut pushed = pin!(self.pushed.notified());
+            pushed.as_mut().enable();
+
+            if let Some((id, queue, value)) = self.try_dequeue(queues).await? {
+                trace!(id, queue, bytes = value.len(); "Pulling next task for processing");
+                let value = decode(&value)?;
+                return Ok((id, queue, value));
+            }
+
+            match self.next_due().await? {
+                // Wake up when the next task is due back in its queue, since it may belong to one of ours.
+                Some(due_at) => {
+                    let wait = Duration::from_millis((due_at - now()).max(0) as u64);
+                    let _ = tokio::time::timeout(wait, pushed).await;
+                }
+                None => pushed.await,
+            }
+        }
+    }
+
+    async fn publish_result<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        trace!(id, bytes = output.len(); "Publishing completed task");
+
+        let retention = self.retention;
+        let published = self.with_conn(move |conn| {
+            let task_id = String::from(&id);
+            let now = now();
+            let tx = conn.transaction()?;
+            // A task whose lease expired before its result was published is acknowledged all the same.
+            let updated = tx.execute(
+                "UPDATE tasks SET status = ?1, lease_expires_at = NULL, updated_at = ?2 WHERE id = ?3 AND status IN (?4, ?5)",
+                params![COMPLETED, now, task_id, PROCESSING, ENQUEUED],
+            )?;
+            if updated == 0 {
+                return Ok(false);
+            }
+
+            tx.execute(
+                "INSERT INTO task_results (task_id, output, created_at) VALUES (?1, ?2, ?3)",
+                params![task_id, output, now],
+            )?;
+            tx.commit()?;
+            expire_results(conn, retention)?;
+            Ok(true)
+        })
+        .await?;
+        if !published {
+            warn!(id; "Ignoring result of task that's no longer being processed");
+            return Ok(());
+        }
+
+        self.published.notify_waiters();
+        // The task's concurrency key may have been holding back another task.
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+
+    async fn retry<V>(&self, id: Self::Id, value: V, delay: Duration) -> Result<(), Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        trace!(id, bytes = value.len(), delay:?; "Retrying task");
+
+        let updated = self
+            .with_conn(move |conn| {
+                let now = now();
+                let available_at = now + delay.as_millis() as i64;
+                let updated = conn.execute(
+                    r#"
+                    UPDATE tasks SET status = ?1, value = ?2, lease_expires_at = NULL, available_at = ?3, updated_at = ?4
+                    WHERE id = ?5 AND status IN (?6, ?7)
+                    "#,
+                    params![RETRYING, value, available_at, now, String::from(&id), PROCESSING, ENQUEUED],
+                )?;
+                Ok(updated)
+            })
+            .await?;
+        if updated == 0 {
+            warn!(id; "Ignoring retry of task that's no longer being processed");
+            return Ok(());
+        }
+
+        // Dequeuers need to know when to check again, and the task's concurrency key may have been holding back another.
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+
+    async fn dead_letter<S>(&self, id: Self::Id, output: S) -> Result<(), Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        trace!(id, bytes = output.len(); "Moving failed task to dead-letter area");
+
+        let buried = self.with_conn(move |conn| {
+            let task_id = String::from(&id);
+            let now = now();
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE tasks SET status = ?1, lease_expires_at = NULL, updated_at = ?2 WHERE id = ?3 AND status IN (?4, ?5)",
+                params![FAILED, now, task_id, PROCESSING, ENQUEUED],
+            )?;
+            if updated == 0 {
+                return Ok(false);
+            }
+
+            tx.execute(
+                "INSERT INTO task_results (task_id, output, created_at) VALUES (?1, ?2, ?3)",
+                params![task_id, output, now],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await?;
+        if !buried {
+            warn!(id; "Ignoring failure of task that's no longer being processed");
+            return Ok(());
+        }
+
+        self.published.notify_waiters();
+        // The task's concurrency key may have been holding back another task.
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+
+    async fn renew_leases(&self, ids: &[Self::Id]) -> Result<(), Self::Error> {
+        let ids = ids.iter().map(String::from).collect::<Vec<_>>();
+        let lease_timeout = self.lease_timeout;
+        self.with_conn(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+            for id in ids {
+                tx.execute(
+                    "UPDATE tasks SET lease_expires_at = ?1, updated_at = ?2 WHERE id = ?3 AND status = ?4",
+                    params![now + lease_timeout.as_millis() as i64, now, id, PROCESSING],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn postpone(&self, id: Self::Id, delay: Duration) -> Result<(), Self::Error> {
+        trace!(id, delay:?; "Postponing task");
+
+        // The task stays enqueued in its place, but isn't dequeued until it's available again.
+        let updated = self
+            .with_conn(move |conn| {
+                let now = now();
+                let updated = conn.execute(
+                    r#"
+                    UPDATE tasks SET status = ?1, lease_expires_at = NULL, available_at = ?2, updated_at = ?3
+                    WHERE id = ?4 AND status IN (?5, ?1)
+                    "#,
+                    params![ENQUEUED, now + delay.as_millis() as i64, now, String::from(&id), PROCESSING],
+                )?;
+                Ok(updated)
+            })
+            .await?;
+        if updated == 0 {
+            warn!(id; "Ignoring postponement of task that's no longer being processed");
+            return Ok(());
+        }
+
+        // Dequeuers need to know when to check again, and the task's concurrency key may have been holding back another.
+        self.pushed.notify_waiters();
+        Ok(())
+    }
+}
+
+impl QueryStore for TasksInSqlite {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn state(&self) -> Result<StoreState, Self::Error> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(&format!("{SELECT_STORED_TASKS} ORDER BY t.queue, t.seq"))?;
+
+            let mut state = StoreState::default();
+            for row in stmt.query_map([], stored_task_row)? {
+                let (task, _) = stored_task(row?)?;
+                state.insert(task);
+            }
+
+            Ok(state)
+        })
+        .await
+    }
+
+    async fn enqueued(&self, queues: &[String]) -> Result<usize, Self::Error> {
+        let queues = queues.to_vec();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT COUNT(*) FROM tasks WHERE queue = ?1 AND status = ?2")?;
+
+            let mut enqueued = 0;
+            for queue in queues {
+                enqueued += stmt.query_row(params![queue, ENQUEUED], |row| row.get::<_, usize>(0))?;
+            }
+
+            Ok(enqueued)
+        })
+        .await
+    }
+
+    async fn task(&self, id: Self::Id) -> Result<Option<StoredTask>, Self::Error> {
+        self.with_conn(move |conn| {
+            let sql = format!("{SELECT_STORED_TASKS} WHERE t.id = ?1");
+            let Some(row) = conn.query_row(&sql, params![String::from(&id)], stored_task_row).optional()? else {
+                return Ok(None);
+            };
+
+            let (mut task, output) = stored_task(row)?;
+            task.result = output.map(|output| decode(&output)).transpose()?;
+            Ok(Some(task))
+        })
+        .await
+    }
+
+    async fn dead_tasks(&self) -> Result<Vec<DeadTask>, Self::Error> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(&format!("{SELECT_STORED_TASKS} WHERE t.status = ?1 ORDER BY t.seq"))?;
+
+            let mut dead_tasks = vec![];
+            for row in stmt.query_map(params![FAILED], stored_task_row)? {
+                let (task, output) = stored_task(row?)?;
+                let output = output.ok_or_else(|| anyhow!("dead task {} has no result", task.id))?;
+                dead_tasks.push(DeadTask { task, failure: decode(&output)? });
+            }
+
+            Ok(dead_tasks)
+        })
+        .await
+    }
+
+    async fn requeue_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        let requeued = self.with_conn(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+            let mut requeued = 0;
+            for id in Self::dead_ids(&tx, ids)? {
+                let value = tx
+                    .query_row(
+                        "SELECT value FROM tasks WHERE id = ?1 AND status = ?2",
+                        params![id, FAILED],
+                        |row| row.get::<_, Vec<u8>>(0),
+                    )
+                    .optional()?;
+                let Some(value) = value else {
+                    continue;
+                };
+
+                let mut task: Task = decode(&value)?;
+                task.attempts = 0;
+                tx.execute(
+                    "UPDATE tasks SET status = ?1, value = ?2, updated_at = ?3 WHERE id = ?4",
+                    params![ENQUEUED, encode(&task)?, now, id],
+                )?;
+                tx.execute("DELETE FROM task_results WHERE task_id = ?1", params![id])?;
+                requeued += 1;
+            }
+            tx.commit()?;
+            Ok(requeued)
+        })
+        .await?;
+
+        self.pushed.notify_waiters();
+        Ok(requeued)
+    }
+
+    async fn purge_dead(&self, ids: Option<Vec<Self::Id>>) -> Result<usize, Self::Error> {
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let mut purged = 0;
+            for id in Self::dead_ids(&tx, ids)? {
+                purged += tx.execute("DELETE FROM tasks WHERE id = ?1 AND status = ?2", params![id, FAILED])?;
+            }
+            tx.commit()?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    async fn cancel<S>(&self, id: Self::Id, output: S) -> Result<Cancellation, Self::Error>
+    where
+        S: Serialize + Send,
+    {
+        let output = encode(&output)?;
+        let id = String::from(&id);
+
+        let cancellation = self.with_conn(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+            let cancelled = tx.execute(
+                r#"
+                UPDATE tasks SET status = ?1, available_at = NULL, updated_at = ?2
+                WHERE id = ?3 AND status IN (?4, ?5, ?6)
+                "#,
+                params![COMPLETED, now, id, ENQUEUED, SCHEDULED, RETRYING],
+            )?;
+
+            let cancellation = if cancelled > 0 {
+                tx.execute(
+                    "INSERT INTO task_results (task_id, output, created_at) VALUES (?1, ?2, ?3)",
+                    params![id, output, now],
+                )?;
+                tx.commit()?;
+                Cancellation::Cancelled
+            } else {
+                let status = tx
+                    .query_row("SELECT status FROM tasks WHERE id = ?1", params![id], |row| row.get::<_, String>(0))
+                    .optional()?;
+                match status.as_deref() {
+                    Some(PROCESSING) => Cancellation::Processing,
+                    _ => Cancellation::NotFound,
+                }
+            };
+            Ok(cancellation)
+        })
+        .await?;
+
+        if cancellation == Cancellation::Cancelled {
+            self.published.notify_waiters();
+        }
+        Ok(cancellation)
+    }
+}
+
+impl ScheduleStore for TasksInSqlite {
+    type Id = TaskId;
+    type Error = anyhow::Error;
+
+    async fn schedules(&self) -> Result<Vec<StoredSchedule>, Self::Error> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT name, cron, queue, definition, args, kwargs, last_fired_at FROM schedules ORDER BY name",
+            )?;
+
+            let rows = stmt.query_map([], |row| {
+                let schedule = RecurringSchedule {
+                    name: row.get(0)?,
+                    cron: row.get(1)?,
+                    queue: row.get(2)?,
+                    definition: row.get(3)?,
+                    args: row.get(4)?,
+                    kwargs: row.get(5)?,
+                };
+                Ok(StoredSchedule { schedule, last_fired_at: row.get::<_, i64>(6)? as u64 })
+            })?;
+
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn save_schedule(&self, schedule: RecurringSchedule) -> Result<StoredSchedule, Self::Error> {
+        self.with_conn(move |conn| {
+            let last_fired_at = conn.query_row(
+                r#"
+                INSERT INTO schedules (name, cron, queue, definition, args, kwargs, last_fired_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (name) DO UPDATE SET
+                    cron = excluded.cron,
+                    queue = excluded.queue,
+                    definition = excluded.definition,
+                    args = excluded.args,
+                    kwargs = excluded.kwargs
+                RETURNING last_fired_at
+                "#,
+                params![
+                    schedule.name,
+                    schedule.cron,
+                    schedule.queue,
+                    schedule.definition,
+                    schedule.args,
+                    schedule.kwargs,
+                    now()
+                ],
+                |row| row.get::<_, i64>(0),
+            )?;
+
+            Ok(StoredSchedule { schedule, last_fired_at: last_fired_at as u64 })
+        })
+        .await
+    }
+
+    async fn remove_schedule(&self, name: &str) -> Result<bool, Self::Error> {
+        let name = name.to_string();
+        self.with_conn(move |conn| {
+            let removed = conn.execute("DELETE FROM schedules WHERE name = ?1", params![name])?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn fire_schedule<V>(&self, name: &str, due_at: SystemTime, value: V) -> Result<Option<Self::Id>, Self::Error>
+    where
+        V: Serialize + Send,
+    {
+        let value = encode(&value)?;
+        let (priority, concurrency_key, concurrency_limit) = rank(&value)?;
+        let due_at = due_at.duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+        let id = TaskId::generate();
+        let name = name.to_string();
+
+        // Claim the occurrence and submit its task together, so it fires once even across servers sharing the database.
+        let fired = self.with_conn(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+            let queue = tx
+                .query_row(
+                    "UPDATE schedules SET last_fired_at = MAX(?1, ?2) WHERE name = ?3 AND last_fired_at < ?2 RETURNING queue",
+                    params![now, due_at, name],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+
+            let Some(queue) = queue else {
+                return Ok(false);
+            };
+            tx.execute(
+                r#"
+                INSERT INTO tasks (id, queue, status, priority, concurrency_key, concurrency_limit, value, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)
+                "#,
+                params![String::from(&id), queue, ENQUEUED, priority, concurrency_key, concurrency_limit, value, now],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await?;
+        if !fired {
+            return Ok(None);
+        }
+
+        self.pushed.notify_waiters();
+        Ok(Some(id))
+    }
+}
+
+/// Remove completed tasks whose results haven't been pulled within the retention period.
+fn expire_results(conn: &Connection, retention: Option<Duration>) -> Result<(), anyhow::Error> {
+    // A retention reaching back before the Unix epoch keeps results forever rather than overflowing.
+    let cutoff = retention.and_then(|retention| now().checked_sub(i64::try_from(retention.as_millis()).ok()?));
+    if let Some(cutoff) = cutoff {
+        conn.execute(
+            "DELETE FROM tasks WHERE status = ?1 AND id IN (SELECT task_id FROM task_results WHERE created_at < ?2)",
+            params![COMPLETED, cutoff],
+        )?;
+    }
+    Ok(())
+}
+
+/// The columns selected by `SELECT_STORED_TASKS`.
+type StoredTaskRow = (String, String, String, Vec<u8>, i64, Option<Vec<u8>>, Option<i64>);
+
+fn stored_task_row(row: &Row) -> rusqlite::Result<StoredTaskRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+}
+
+/// Build a stored task from a row selected by `SELECT_STORED_TASKS`, returning it with its serialized output if it has one.
+fn stored_task(row: StoredTaskRow) -> Result<(StoredTask, Option<Vec<u8>>), anyhow::Error> {
+    let (id, queue, status, value, created_at, output, finished_at) = row;
+    let Task { definition, args, kwargs, priority, attempts, .. } = decode(&value)?;
+    let task = StoredTask {
+        id: TaskId::try_from(id)?,
+        queue,
+        status: stored_status(&status)?,
+        priority,
+        definition,
+        args,
+        kwargs,
+        attempts,
+        created_at: created_at as u64,
+        finished_at: finished_at.map(|finished_at| finished_at as u64),
+        result: None,
+    };
+    Ok((task, output))
+}
+
+/// The priority, concurrency key and concurrency limit columns of a serialized task value.
+fn rank(value: &[u8]) -> Result<(i32, Option<String>, u32), anyhow::Error> {
+    let Ranked { priority, concurrency_key, concurrency_limit } = decode(value)?;
+    Ok((priority, concurrency_key, concurrency_limit.unwrap_or(1)))
+}
+
+fn stored_status(status: &str) -> Result<StoredTaskStatus, anyhow::Error> {
+    match status {
+        ENQUEUED => Ok(StoredTaskStatus::Enqueued),
+        PROCESSING => Ok(StoredTaskStatus::Processing),
+        COMPLETED => Ok(StoredTaskStatus::Completed),
+        RETRYING => Ok(StoredTaskStatus::Retrying),
+        FAILED => Ok(StoredTaskStatus::Failed),
+        SCHEDULED => Ok(StoredTaskStatus::Scheduled),
+        _ => Err(anyhow!("unknown task status '{status}'")),
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::{Task, TaskResult};
+    use std::path::PathBuf;
+
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mavrik-{}-{name}.sqlite3", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_from_requested_queues_in_fifo_order() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let first_id = store.push("email", Task::for_test("First")).await?;
+        store.push("billing", Task::for_test("Charge")).await?;
+        let second_id = store.push("email", Task::for_test("Second")).await?;
+
+        let queues = vec!["email".to_string()];
+        let (id, queue, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, queue.as_str(), value), (first_id, "email", Task::for_test("First")));
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (second_id, Task::for_test("Second")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_pulls_higher_priority_tasks_first() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        for (definition, priority) in [("Newsletter", 0), ("Urgent", 10), ("Cleanup", -5), ("Digest", 0), ("Reset", 10)] {
+            store.push("default", Task { priority, ..Task::for_test(definition) }).await?;
+        }
+
+        let mut dequeued = vec![];
+        for _ in 0..5 {
+            let (_, _, value) = store.dequeue::<Task>(&["default".to_string()]).await?;
+            dequeued.push(value.definition);
+        }
+        assert_eq!(dequeued, ["Urgent", "Reset", "Newsletter", "Digest", "Cleanup"]);
+        assert_eq!(store.state().await?.queues["default"][1].priority, 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_holds_back_tasks_whose_concurrency_key_is_at_its_limit() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let keyed = |definition: &str, key: &str, limit: Option<u32>| Task {
+            concurrency_key: Some(key.to_string()),
+            concurrency_limit: limit,
+            ..Task::for_test(definition)
+        };
+        let first_id = store.push("default", keyed("First", "account:1", None)).await?;
+        store.push("default", keyed("Second", "account:1", None)).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+        store.push("default", keyed("Report", "reports", Some(2))).await?;
+
+        let queues = vec!["default".to_string()];
+        let mut dequeued = vec![];
+        for _ in 0..3 {
+            let (_, _, value) = store.dequeue::<Task>(&queues).await?;
+            dequeued.push(value.definition);
+        }
+        assert_eq!(dequeued, ["First", "Report", "Report"]);
+        let blocked = tokio::time::timeout(Duration::from_millis(50), store.dequeue::<Task>(&queues)).await;
+        assert!(blocked.is_err());
+
+        store.publish_result(first_id, TaskResult::Success { result: "6".to_string() }).await?;
+        let (_, _, value) = store.dequeue::<Task>(&queues).await?;
+        assert_eq!(value.definition, "Second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dequeue_waits_for_push() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let queues = vec!["default".to_string()];
+
+        let (result, pushed) = tokio::join!(
+            store.dequeue::<Task>(&queues),
+            store.push("default", Task::for_test("Later")),
+        );
+        let (id, _, value) = result?;
+        assert_eq!((id, value), (pushed?, Task::for_test("Later")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pull_returns_published_result_once() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+
+        let success = TaskResult::Success { result: "6".to_string() };
+        let (result, published) = tokio::join!(
+            store.pull::<TaskResult>(task_id),
+            store.publish_result(task_id, success.clone())
+        );
+        published?;
+        assert_eq!(result?, success);
+        assert!(store.state().await?.queues.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_is_looked_up_without_taking_its_result() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Report")).await?;
+        let found = store.task(task_id).await?.expect("task should be stored");
+        assert_eq!(found.status, StoredTaskStatus::Enqueued);
+        assert_eq!(found.attempts, 0);
+        assert!(found.result.is_none());
+
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        assert_eq!(store.task(task_id).await?.unwrap().status, StoredTaskStatus::Processing);
+
+        let success = TaskResult::Success { result: "6".to_string() };
+        store.publish_result(task_id, success.clone()).await?;
+        let found = store.task(task_id).await?.unwrap();
+        assert_eq!(found.status, StoredTaskStatus::Completed);
+        assert!(found.finished_at.is_some());
+        assert_eq!(found.result, Some(success.clone()));
+
+        // Looking the task up doesn't take its result.
+        let pulled: TaskResult = store.pull(task_id).await?;
+        assert_eq!(pulled, success);
+        assert!(store.task(task_id).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_removes_tasks_that_havent_started() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let processing_id = store.push("default", Task::for_test("Processing")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        let enqueued_id = store.push("default", Task::for_test("Enqueued")).await?;
+        let run_at = SystemTime::now() + Duration::from_secs(60);
+        let scheduled_id = store.schedule("default", Task::for_test("Scheduled"), run_at).await?;
+
+        assert_eq!(store.cancel(enqueued_id, TaskResult::Cancelled).await?, Cancellation::Cancelled);
+        assert_eq!(store.cancel(scheduled_id, TaskResult::Cancelled).await?, Cancellation::Cancelled);
+        assert_eq!(store.cancel(processing_id, TaskResult::Cancelled).await?, Cancellation::Processing);
+        assert_eq!(store.cancel(enqueued_id, TaskResult::Cancelled).await?, Cancellation::NotFound);
+
+        let result: TaskResult = store.pull(scheduled_id).await?;
+        assert_eq!(result, TaskResult::Cancelled);
+        let cancelled = store.task(enqueued_id).await?.expect("task is stored");
+        assert_eq!(cancelled.status, StoredTaskStatus::Completed);
+        assert_eq!(cancelled.result, Some(TaskResult::Cancelled));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn state_reports_tasks_by_queue_and_status() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let charge_id = store.push("billing", Task::for_test("Charge")).await?;
+        let email_id = store.push("email", Task::for_test("SendEmail")).await?;
+        store.dequeue::<Task>(&["email".to_string()]).await?;
+
+        let state = store.state().await?;
+        assert_eq!(state.queues["billing"][0].id, charge_id);
+        assert_eq!(state.queues["billing"][0].definition, "Charge");
+        assert_eq!(state.queues["billing"][0].status, StoredTaskStatus::Enqueued);
+        assert_eq!(state.queues["email"][0].id, email_id);
+        assert_eq!(state.queues["email"][0].status, StoredTaskStatus::Processing);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enqueued_counts_tasks_waiting_in_the_given_queues() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        store.push("billing", Task::for_test("Charge")).await?;
+        store.push("billing", Task::for_test("Refund")).await?;
+        store.push("email", Task::for_test("SendEmail")).await?;
+        store.push("reports", Task::for_test("Export")).await?;
+        let (postponed_id, _, _): (TaskId, String, Task) = store.dequeue(&["billing".to_string()]).await?;
+        store.dequeue::<Task>(&["email".to_string()]).await?;
+        store.postpone(postponed_id, Duration::from_secs(60)).await?;
+
+        let queues = vec!["billing".to_string(), "email".to_string()];
+        assert_eq!(store.enqueued(&queues).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn results_are_expired_after_retention() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, Some(Duration::from_millis(200)))?;
+        let old_id = store.push("default", Task::for_test("Old")).await?;
+        let new_id = store.push("default", Task::for_test("New")).await?;
+        let queues = vec!["default".to_string()];
+        store.dequeue::<Task>(&queues).await?;
+        store.dequeue::<Task>(&queues).await?;
+
+        let success = TaskResult::Success { result: "6".to_string() };
+        store.publish_result(old_id, success.clone()).await?;
+        std::thread::sleep(Duration::from_millis(300));
+        store.publish_result(new_id, success).await?;
+
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"].len(), 1);
+        assert_eq!(state.queues["default"][0].id, new_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_dequeued_again() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?.with_lease_timeout(Duration::from_millis(100));
+        let task_id = store.push("default", Task::for_test("Slow")).await?;
+        let queues = vec!["default".to_string()];
+        store.dequeue::<Task>(&queues).await?;
+
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!(id, task_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_result_acknowledges_lease() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?.with_lease_timeout(Duration::from_millis(100));
+        let task_id = store.push("default", Task::for_test("Done")).await?;
+        let queues = vec!["default".to_string()];
+        store.dequeue::<Task>(&queues).await?;
+        store.publish_result(task_id, TaskResult::Success { result: "6".to_string() }).await?;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(store.try_dequeue(&queues).await?.is_none());
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Completed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retried_tasks_are_dequeued_again_after_delay() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Flaky")).await?;
+        let queues = vec!["default".to_string()];
+        let (_, _, mut value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+
+        value.attempts += 1;
+        store.retry(task_id, value.clone(), Duration::from_millis(100)).await?;
+        assert!(store.try_dequeue(&queues).await?.is_none());
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Retrying);
+
+        let (id, _, retried): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, retried), (task_id, value));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn postponed_tasks_stay_enqueued_in_their_place() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let first_id = store.push("default", Task::for_test("First")).await?;
+        let queues = vec!["default".to_string()];
+        store.dequeue::<Task>(&queues).await?;
+
+        store.postpone(first_id, Duration::from_millis(100)).await?;
+        assert!(store.try_dequeue(&queues).await?.is_none());
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Enqueued);
+
+        store.push("default", Task::for_test("Second")).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (first_id, Task::for_test("First")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scheduled_tasks_are_dequeued_once_due() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let run_at = SystemTime::now() + Duration::from_millis(100);
+        let task_id = store.schedule("default", Task::for_test("Later"), run_at).await?;
+        let queues = vec!["default".to_string()];
+        assert!(store.try_dequeue(&queues).await?.is_none());
+        assert_eq!(store.state().await?.queues["default"][0].status, StoredTaskStatus::Scheduled);
+
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (task_id, Task::for_test("Later")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_values_are_kept_as_message_pack() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let binary = Task { args: vec![0x92, 0x01, 0xff].into(), priority: 3, ..Task::for_test("Binary") };
+        let task_id = store.push("default", binary.clone()).await?;
+
+        let (kind, priority) = store
+            .with_conn(|conn| {
+                let sql = "SELECT typeof(value), priority FROM tasks";
+                Ok(conn.query_row(sql, [], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?)))?)
+            })
+            .await?;
+        assert_eq!((kind.as_str(), priority), ("blob", 3));
+
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&["default".to_string()]).await?;
+        assert_eq!((id, value), (task_id, binary));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dead_tasks_are_kept_until_requeued_or_purged() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, Some(Duration::ZERO))?;
+        let failure = TaskResult::Failure { class: "RuntimeError".to_string(), message: "oops".to_string(), backtrace: vec![] };
+        let queues = vec!["default".to_string()];
+        let requeued_id = store.push("default", Task::for_test("Broken")).await?;
+        let purged_id = store.push("default", Task::for_test("Broken")).await?;
+        for id in [requeued_id, purged_id] {
+            let (_, _, mut value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+            value.attempts = 2;
+            store.retry(id, value, Duration::ZERO).await?;
+            store.dequeue::<Task>(&queues).await?;
+            store.dead_letter(id, failure.clone()).await?;
+        }
+
+        assert_eq!(store.pull::<TaskResult>(requeued_id).await?, failure);
+        let dead_tasks = store.dead_tasks().await?;
+        assert_eq!(dead_tasks.iter().map(|dead| dead.task.id).collect::<Vec<_>>(), vec![requeued_id, purged_id]);
+        assert_eq!(dead_tasks[0].failure, failure);
+
+        assert_eq!(store.purge_dead(Some(vec![purged_id])).await?, 1);
+        assert_eq!(store.requeue_dead(None).await?, 1);
+        let (id, _, value): (TaskId, String, Task) = store.dequeue(&queues).await?;
+        assert_eq!((id, value), (requeued_id, Task::for_test("Broken")));
+        assert!(store.dead_tasks().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_requeues_tasks_that_were_processing() -> Result<(), anyhow::Error> {
+        let path = db_path("requeues-processing");
+        let store = TasksInSqlite::open(&path, QueueOrder::Fifo, None)?;
+        let task_id = store.push("default", Task::for_test("Interrupted")).await?;
+        store.dequeue::<Task>(&["default".to_string()]).await?;
+        drop(store);
+
+        let store = TasksInSqlite::open(&path, QueueOrder::Fifo, None)?;
+        let state = store.state().await?;
+        assert_eq!(state.queues["default"][0].id, task_id);
+        assert_eq!(state.queues["default"][0].status, StoredTaskStatus::Enqueued);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fire_schedule_submits_each_occurrence_once() -> Result<(), anyhow::Error> {
+        let store = TasksInSqlite::open(":memory:", QueueOrder::Fifo, None)?;
+        let schedule = RecurringSchedule {
+            name: "report".to_string(),
+            cron: "* * * * *".to_string(),
+            queue: "default".to_string(),
+            definition: "Report".to_string(),
+            args: "[]".into(),
+            kwargs: "{}".into(),
+        };
+        let added = store.save_schedule(schedule.clone()).await?;
+        let due_at = added.last_fired() + Duration::from_secs(60);
+
+        assert!(store.fire_schedule("report", added.last_fired(), Task::for_test("Report")).await?.is_none());
+        let task_id = store.fire_schedule("report", due_at, Task::for_test("Report")).await?;
+        assert!(task_id.is_some());
+        assert!(store.fire_schedule("report", due_at, Task::for_test("Report")).await?.is_none());
+
+        let replaced = store.save_schedule(schedule.clone()).await?;
+        assert!(replaced.last_fired() >= due_at);
+        assert_eq!(store.schedules().await?, vec![replaced]);
+
+        let (id, _, _): (TaskId, String, Task) = store.dequeue(&["default".to_string()]).await?;
+        assert_eq!(Some(id), task_id);
+        assert!(store.remove_schedule("report").await?);
+        assert!(!store.remove_schedule("report").await?);
+        Ok(())
+    }
+}
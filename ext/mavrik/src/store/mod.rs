@@ -1,7 +1,13 @@
 mod store;
+mod in_file;
 mod in_memory;
+mod in_sqlite;
+mod queue_order;
 mod store_state;
 
 pub use store::*;
+pub use in_file::*;
 pub use in_memory::*;
+pub use in_sqlite::*;
+pub use queue_order::*;
 pub use store_state::*;
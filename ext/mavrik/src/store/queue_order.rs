@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// The order in which entries are pulled from a queue.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOrder {
+    /// First in, first out. The oldest entry is pulled first.
+    #[default]
+    Fifo,
+
+    /// Last in, first out. The newest entry is pulled first.
+    Lifo,
+}
@@ -1,7 +1,25 @@
-use crate::store::store_state::StoreState;
+use crate::messaging::RecurringSchedule;
+use crate::store::store_state::{DeadTask, StoreState, StoredSchedule, StoredTask};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+/// How long a dequeued entry is leased for by default before it's returned to its queue.
+pub const DEFAULT_LEASE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What cancelling an entry did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancellation {
+    /// The entry hadn't started processing, so it was removed from its queue and given the cancelled output.
+    Cancelled,
+
+    /// The entry is being processed, so whatever is processing it has to be stopped.
+    Processing,
+
+    /// The entry has already finished, or isn't stored.
+    NotFound,
+}
 
 /// A store that can have new entries pushed to it.
 pub trait PushStore {
@@ -10,6 +28,11 @@ pub trait PushStore {
 
     /// Push a new entry to the store.
     ///
+    /// # Arguments
+    ///
+    /// `queue` - The name of the queue to push the entry onto.
+    /// `value` - The entry to push.
+    ///
     /// # Returns
     ///
     /// The ID of the entry that was pushed.
@@ -22,6 +45,28 @@ pub trait PushStore {
     where
         S: AsRef<str> + Send,
         V: Serialize + Send;
+
+    /// Push a new entry to the store that can't be pulled until `run_at` has passed.
+    ///
+    /// # Arguments
+    ///
+    /// `queue` - The name of the queue to push the entry onto.
+    /// `value` - The entry to push.
+    /// `run_at` - When the entry becomes due. Entries due in the past can be pulled immediately.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the entry that was pushed.
+    ///
+    fn schedule<S, V>(
+        &self,
+        queue: S,
+        value: V,
+        run_at: SystemTime,
+    ) -> impl Future<Output = Result<Self::Id, Self::Error>> + Send
+    where
+        S: AsRef<str> + Send,
+        V: Serialize + Send;
 }
 
 /// A store that can have entries pulled from it.
@@ -47,15 +92,28 @@ pub trait ProcessStore {
 
     /// Pull the next entry from the store.
     ///
+    /// The entry is leased to the caller until its result is published. If the lease expires first, the entry is
+    /// returned to its queue to be pulled again, so callers processing entries for longer should renew their leases.
+    ///
+    /// # Arguments
+    ///
+    /// `queues` - The names of the queues to pull from, in order of preference.
+    ///
     /// # Returns
     ///
-    /// A tuple containing the ID of the entry and the entry itself.
+    /// A tuple containing the ID of the entry, the name of the queue it was pulled from, and the entry itself.
     ///
-    fn dequeue<D>(&self) -> impl Future<Output = Result<(Self::Id, D), Self::Error>> + Send
+    fn dequeue<D>(
+        &self,
+        queues: &[String],
+    ) -> impl Future<Output = Result<(Self::Id, String, D), Self::Error>> + Send
     where
         D: DeserializeOwned;
 
-    /// Publish the result of processing an entry.
+    /// Publish the result of processing an entry, acknowledging its lease.
+    ///
+    /// A result for an entry that's no longer being processed, e.g. because another attempt already acknowledged it,
+    /// is ignored.
     ///
     /// # Arguments
     ///
@@ -69,6 +127,61 @@ pub trait ProcessStore {
     ) -> impl Future<Output = Result<(), Self::Error>> + Send
     where
         S: Serialize + Send;
+
+    /// Return an entry that failed processing to its queue, to be pulled again once `delay` has passed.
+    ///
+    /// Like publishing a result, this acknowledges the entry's lease. The entry keeps its ID.
+    ///
+    /// # Arguments
+    ///
+    /// `id` - The ID of the entry that failed processing.
+    /// `value` - The entry to store in place of the original, e.g. with its attempt count updated.
+    /// `delay` - How long to wait before the entry can be pulled again.
+    ///
+    fn retry<V>(
+        &self,
+        id: Self::Id,
+        value: V,
+        delay: Duration,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send
+    where
+        V: Serialize + Send;
+
+    /// Move an entry that failed for good to the dead-letter area, acknowledging its lease.
+    ///
+    /// Dead entries are kept, along with the result of their final attempt, until they're re-enqueued or purged.
+    ///
+    /// # Arguments
+    ///
+    /// `id` - The ID of the entry that failed processing.
+    /// `output` - The result of the entry's final attempt.
+    ///
+    fn dead_letter<S>(
+        &self,
+        id: Self::Id,
+        output: S,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send
+    where
+        S: Serialize + Send;
+
+    /// Extend the leases of entries being processed, so they last the full lease timeout from now.
+    ///
+    /// # Arguments
+    ///
+    /// `ids` - The IDs of the entries being processed. Entries that are no longer being processed are skipped.
+    ///
+    fn renew_leases(&self, ids: &[Self::Id]) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Hold back an entry being processed until `delay` has passed, acknowledging its lease.
+    ///
+    /// Unlike retrying, the entry is left unchanged and stays enqueued, keeping its place in its queue.
+    ///
+    /// # Arguments
+    ///
+    /// `id` - The ID of the entry to hold back.
+    /// `delay` - How long to wait before the entry can be pulled again.
+    ///
+    fn postpone(&self, id: Self::Id, delay: Duration) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
 /// A store that can be inspected and managed from an external actor.
@@ -76,6 +189,7 @@ pub trait ProcessStore {
 /// This is useful for inspecting the state of the store, removing entries that should no longer be processed,
 /// and so on.
 pub trait QueryStore {
+    type Id;
     type Error;
 
     /// Get the state of the store.
@@ -85,4 +199,143 @@ pub trait QueryStore {
     /// The state of the store.
     ///
     fn state(&self) -> impl Future<Output = Result<StoreState, Self::Error>> + Send;
+
+    /// Count the entries waiting to be processed in the given queues, without loading the entries themselves.
+    ///
+    /// # Arguments
+    ///
+    /// `queues` - The names of the queues to count entries in.
+    ///
+    /// # Returns
+    ///
+    /// The number of enqueued entries across the queues.
+    ///
+    fn enqueued(&self, queues: &[String]) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+
+    /// Look up a single entry without waiting for it to finish or removing its result.
+    ///
+    /// # Arguments
+    ///
+    /// `id` - The ID of the entry to look up.
+    ///
+    /// # Returns
+    ///
+    /// The entry, including its result if it has finished, or `None` if it isn't stored.
+    ///
+    fn task(&self, id: Self::Id) -> impl Future<Output = Result<Option<StoredTask>, Self::Error>> + Send;
+
+    /// Get the entries in the dead-letter area.
+    ///
+    /// # Returns
+    ///
+    /// The dead entries, oldest first, along with the results of their final attempts.
+    ///
+    fn dead_tasks(&self) -> impl Future<Output = Result<Vec<DeadTask>, Self::Error>> + Send;
+
+    /// Return entries in the dead-letter area to their queues, with their attempts reset.
+    ///
+    /// # Arguments
+    ///
+    /// `ids` - The IDs of the dead entries to re-enqueue, or `None` for all of them.
+    ///
+    /// # Returns
+    ///
+    /// The number of entries re-enqueued.
+    ///
+    fn requeue_dead(&self, ids: Option<Vec<Self::Id>>) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+
+    /// Remove entries from the dead-letter area.
+    ///
+    /// # Arguments
+    ///
+    /// `ids` - The IDs of the dead entries to remove, or `None` for all of them.
+    ///
+    /// # Returns
+    ///
+    /// The number of entries removed.
+    ///
+    fn purge_dead(&self, ids: Option<Vec<Self::Id>>) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+
+    /// Cancel an entry that hasn't started processing, finishing it with the given output instead.
+    ///
+    /// Entries that are enqueued, scheduled or waiting to be retried are removed from their queues, and their output
+    /// can be pulled like any other. Entries being processed are left alone.
+    ///
+    /// # Arguments
+    ///
+    /// `id` - The ID of the entry to cancel.
+    /// `output` - The output to finish the entry with.
+    ///
+    /// # Returns
+    ///
+    /// What cancelling the entry did.
+    ///
+    fn cancel<S>(&self, id: Self::Id, output: S) -> impl Future<Output = Result<Cancellation, Self::Error>> + Send
+    where
+        S: Serialize + Send;
+}
+
+/// A store that keeps recurring schedules, and records when they fire so each occurrence is only submitted once.
+pub trait ScheduleStore {
+    type Id;
+    type Error;
+
+    /// Get the stored schedules.
+    ///
+    /// # Returns
+    ///
+    /// The stored schedules, ordered by name, along with when they last fired.
+    ///
+    fn schedules(&self) -> impl Future<Output = Result<Vec<StoredSchedule>, Self::Error>> + Send;
+
+    /// Add a schedule, or replace the schedule with the same name.
+    ///
+    /// A replaced schedule keeps the time it last fired. A new schedule only fires for occurrences after it's added.
+    ///
+    /// # Arguments
+    ///
+    /// `schedule` - The schedule to save.
+    ///
+    /// # Returns
+    ///
+    /// The saved schedule.
+    ///
+    fn save_schedule(
+        &self,
+        schedule: RecurringSchedule,
+    ) -> impl Future<Output = Result<StoredSchedule, Self::Error>> + Send;
+
+    /// Remove a schedule.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the schedule to remove.
+    ///
+    /// # Returns
+    ///
+    /// Whether the schedule existed.
+    ///
+    fn remove_schedule(&self, name: &str) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Push a new entry for an occurrence of a schedule onto the schedule's queue, unless that occurrence has
+    /// already fired.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the schedule that came due.
+    /// `due_at` - When the occurrence came due.
+    /// `value` - The entry to push.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the entry that was pushed, or `None` if the occurrence already fired or the schedule was removed.
+    ///
+    fn fire_schedule<V>(
+        &self,
+        name: &str,
+        due_at: SystemTime,
+        value: V,
+    ) -> impl Future<Output = Result<Option<Self::Id>, Self::Error>> + Send
+    where
+        V: Serialize + Send;
 }
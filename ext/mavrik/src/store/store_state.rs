@@ -1,26 +1,73 @@
-use crate::messaging::TaskId;
+use crate::messaging::{RawBytes, RecurringSchedule, TaskId, TaskResult};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct StoreState {
-    pub tasks: Vec<StoredTask>,
+    /// Stored tasks, grouped by the name of the queue they were pushed to.
+    pub queues: BTreeMap<String, Vec<StoredTask>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct StoredTask {
     pub id: TaskId,
+    pub queue: String,
     pub status: StoredTaskStatus,
+    pub priority: i32,
     pub definition: String,
-    pub args: String,
-    pub kwargs: String
+    pub args: RawBytes,
+    pub kwargs: RawBytes,
+    pub attempts: u32, // Failed attempts so far
+    pub created_at: u64, // Milliseconds since the Unix epoch
+    pub finished_at: Option<u64>, // Milliseconds since the Unix epoch, once completed or failed for good
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<TaskResult>, // Only included when looking up a single task
+}
+
+/// A task that failed for good, along with the result of its final attempt.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeadTask {
+    #[serde(flatten)]
+    pub task: StoredTask,
+    pub failure: TaskResult,
+}
+
+/// A recurring schedule, along with when it last fired.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StoredSchedule {
+    #[serde(flatten)]
+    pub schedule: RecurringSchedule,
+    pub last_fired_at: u64, // Milliseconds since the Unix epoch
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum StoredTaskStatus {
+    Scheduled,
     Enqueued,
     Processing,
     Completed,
     Retrying,
     Failed
 }
+
+impl StoredSchedule {
+    /// Start tracking a schedule that hasn't fired yet, as of `added_at`.
+    pub fn new(schedule: RecurringSchedule, added_at: SystemTime) -> Self {
+        let last_fired_at = added_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self { schedule, last_fired_at }
+    }
+
+    /// When the schedule last fired, or when it was added if it hasn't fired yet.
+    pub fn last_fired(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.last_fired_at)
+    }
+}
+
+impl StoreState {
+    /// Add a task to the state under the queue it belongs to.
+    pub fn insert(&mut self, task: StoredTask) {
+        self.queues.entry(task.queue.clone()).or_default().push(task);
+    }
+}
@@ -1,65 +1,313 @@
-use std::ops::DerefMut;
-use anyhow::Context;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use anyhow::{anyhow, bail, Context};
+use log::warn;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use crate::{io::{read_object, write_object}, messaging::{MavrikRequest, MavrikResponse}};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use serde::de::IgnoredAny;
+use crate::io::{decode, read_frame, read_object, write_object, Encoding};
+use crate::messaging::{Envelope, Hello, MavrikRequest, MavrikResponse, Welcome, PROTOCOL_VERSION};
+
+/// A response from the server, or why it couldn't be decoded.
+type Response = Result<MavrikResponse, anyhow::Error>;
+
+/// Senders for the responses being waited for, by correlation ID, or `None` once the connection has closed.
+type Waiters = Arc<StdMutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
 
 /// Options for creating a TCP client.
 pub struct TcpClientOptions {
     /// The host to connect to.
     pub host: String,
-    
+
     /// The port to connect on.
-    pub port: u16
+    pub port: u16,
+
+    /// The largest response payload to accept, in bytes.
+    pub max_frame_size: u32
 }
 
 /// The TCP client used to communicate w/ the Mavrik server.
+///
+/// Requests can be made concurrently from many threads. Each gets a correlation ID, and responses are routed back to
+/// the request with the same ID, in whatever order the server answers them.
+///
 #[derive(Debug)]
 pub struct MavrikTcpClient {
-    /// The TCP stream once connected.
-    stream: Mutex<TcpStream>
+    /// The TCP stream's write half once connected.
+    writer: Mutex<OwnedWriteHalf>,
+
+    /// The requests waiting for responses.
+    waiters: Waiters,
+
+    /// The correlation ID of the next request.
+    next_id: AtomicU64,
+
+    /// The task reading responses from the TCP stream's read half.
+    reader: JoinHandle<()>,
+
+    /// The server's answer to the handshake.
+    welcome: Welcome,
+
+    /// The encoding chosen during the handshake.
+    encoding: Encoding
 }
 
 impl MavrikTcpClient {
-    /// Connect to the Mavrik server.
-    /// 
+    /// Connect to the Mavrik server and exchange handshakes with it.
+    ///
+    /// Fails if the server speaks an incompatible version of the protocol. Must be called within a Tokio runtime, which
+    /// reads responses in the background.
+    ///
     /// # Arguments
-    /// 
+    ///
     /// `options` - The options to use when connecting to the server.
-    /// 
+    ///
     /// # Returns
-    /// 
+    ///
     /// A result containing the new client on success.
-    /// 
+    ///
     pub async fn new(options: TcpClientOptions) -> Result<Self, anyhow::Error> {
         let address = format!("{}:{}", options.host, options.port);
-        let stream = TcpStream::connect(address).await.context("failed to connect via TCP")?;
-        let stream = Mutex::new(stream);
-        
-        Ok(Self { stream })
+        let mut stream = TcpStream::connect(&address).await.context("failed to connect via TCP")?;
+
+        write_object(&mut stream, Encoding::Json, Hello::default()).await.context("sending Hello over TCP")?;
+        let welcome: Welcome = read_object(&mut stream, Encoding::Json, options.max_frame_size)
+            .await
+            .with_context(|| format!("receiving Welcome over TCP, is {address} running an older Mavrik?"))?;
+
+        let Welcome { protocol_version, mavrik_version, encoding, .. } = &welcome;
+        if *protocol_version != PROTOCOL_VERSION {
+            bail!(
+                "server at {address} running Mavrik {mavrik_version} speaks protocol version {protocol_version}, \
+                but this client speaks version {PROTOCOL_VERSION}"
+            );
+        }
+        let Some(encoding) = *encoding else {
+            bail!("server at {address} running Mavrik {mavrik_version} supports none of this client's encodings");
+        };
+
+        let (reader, writer) = stream.into_split();
+
+        let waiters = Waiters::new(StdMutex::new(Some(HashMap::new())));
+        let reader = tokio::spawn(read_responses(reader, waiters.clone(), encoding, options.max_frame_size));
+
+        Ok(Self { writer: Mutex::new(writer), waiters, next_id: AtomicU64::new(1), reader, welcome, encoding })
     }
 
-    /// Send a request to the server.
-    /// 
+    /// The server's answer to the handshake, advertising its version and capabilities.
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    /// Send a request to the server and wait for its response.
+    ///
     /// # Arguments
-    /// 
+    ///
     /// `request` - The request to send
     ///
-    pub async fn send(&self, request: &MavrikRequest) -> Result<(), anyhow::Error> {
-        let mut stream = self.stream.lock().await;
-        write_object(stream.deref_mut(), &request).await.context("sending Mavrik request over TCP")?;
+    /// # Returns
+    ///
+    /// The response from the server.
+    ///
+    pub async fn request(&self, request: &MavrikRequest) -> Result<MavrikResponse, anyhow::Error> {
+        let correlation_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (response_tx, response_rx) = oneshot::channel();
+        match self.waiters.lock().expect("waiters lock poisoned").as_mut() {
+            Some(waiters) => waiters.insert(correlation_id, response_tx),
+            None => return Err(anyhow!("connection to server closed")),
+        };
+
+        let envelope = Envelope { correlation_id, message: request };
+        let sent = write_object(&mut *self.writer.lock().await, self.encoding, &envelope).await;
+        if let Err(e) = sent {
+            self.remove_waiter(correlation_id);
+            return Err(e).context("sending Mavrik request over TCP");
+        }
+
+        response_rx.await.map_err(|_| anyhow!("connection to server closed before a response was received"))?
+    }
+
+    fn remove_waiter(&self, correlation_id: u64) {
+        if let Some(waiters) = self.waiters.lock().expect("waiters lock poisoned").as_mut() {
+            waiters.remove(&correlation_id);
+        }
+    }
+}
+
+impl Drop for MavrikTcpClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Read responses from the server and hand them to the requests waiting for them, until the connection fails.
+///
+/// A response that can't be decoded only fails its own request, as long as its correlation ID can be read.
+///
+async fn read_responses(mut reader: OwnedReadHalf, waiters: Waiters, encoding: Encoding, max_frame_size: u32) {
+    loop {
+        let payload = match read_frame(&mut reader, max_frame_size).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                // An oversized response can't be matched to its request, so fail every request rather than leave one
+                // waiting forever.
+                warn!(e:%; "Receiving Mavrik response over TCP failed, closing connection");
+                break;
+            }
+        };
+
+        let (correlation_id, response) = match decode::<Envelope<MavrikResponse>>(encoding, &payload) {
+            Ok(Envelope { correlation_id, message }) => (correlation_id, Ok(message)),
+            Err(e) => match decode::<Envelope<IgnoredAny>>(encoding, &payload) {
+                Ok(Envelope { correlation_id, .. }) => (correlation_id, Err(e.context("deserializing response"))),
+                Err(e) => {
+                    warn!(e:%; "Received Mavrik response without a correlation ID, closing connection");
+                    break;
+                }
+            },
+        };
+
+        let waiter = waiters
+            .lock()
+            .expect("waiters lock poisoned")
+            .as_mut()
+            .and_then(|waiters| waiters.remove(&correlation_id));
+
+        match waiter {
+            Some(waiter) => { let _ = waiter.send(response); },
+            None => warn!(correlation_id, response:?; "Received response for unknown request"),
+        }
+    }
+
+    // Dropping the senders wakes every waiting request with an error.
+    waiters.lock().expect("waiters lock poisoned").take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::DEFAULT_MAX_FRAME_SIZE;
+    use crate::messaging::{Capabilities, TaskId};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    async fn connect(listener: &TcpListener) -> Result<MavrikTcpClient, anyhow::Error> {
+        let options = TcpClientOptions {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr()?.port(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        };
+        MavrikTcpClient::new(options).await
+    }
+
+    /// Accept a connection and answer its handshake with `welcome`.
+    async fn accept(listener: &TcpListener, welcome: impl Fn(&Hello) -> Welcome) -> Result<TcpStream, anyhow::Error> {
+        let (mut stream, _) = listener.accept().await?;
+        let hello: Hello = read_object(&mut stream, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await?;
+        write_object(&mut stream, Encoding::Json, welcome(&hello)).await?;
+        Ok(stream)
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> Result<Envelope<MavrikRequest>, anyhow::Error> {
+        read_object(stream, Encoding::MessagePack, DEFAULT_MAX_FRAME_SIZE).await
+    }
+
+    fn welcome(hello: &Hello) -> Welcome {
+        Welcome::new(hello, Capabilities { max_frame_size: DEFAULT_MAX_FRAME_SIZE, max_concurrent_requests: 64 })
+    }
+
+    #[tokio::test]
+    async fn responses_are_routed_to_their_requests() -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (client, stream) = tokio::join!(connect(&listener), accept(&listener, welcome));
+        let (client, mut stream) = (client?, stream?);
+
+        let slow = client.request(&MavrikRequest::GetStoreState);
+        let fast = client.request(&MavrikRequest::ListDeadTasks);
+        let server = async {
+            let first = read_request(&mut stream).await?;
+            let second = read_request(&mut stream).await?;
+            // Answer out of order: the count goes to whichever request was the dead task listing.
+            for request in [second, first] {
+                let count = if request.message == MavrikRequest::ListDeadTasks { 2 } else { 1 };
+                let message = MavrikResponse::Count(count);
+                let response = Envelope { correlation_id: request.correlation_id, message };
+                write_object(&mut stream, Encoding::MessagePack, &response).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let (slow, fast, served) = tokio::join!(slow, fast, server);
+        served?;
+        assert!(matches!(slow?, MavrikResponse::Count(1)));
+        assert!(matches!(fast?, MavrikResponse::Count(2)));
         Ok(())
     }
 
-    /// Receive a response from the server.
-    /// 
-    /// # Returns
-    /// 
-    /// The response from the server.
-    /// 
-    pub async fn recv(&self) -> Result<MavrikResponse, anyhow::Error> {
-        let mut stream = self.stream.lock().await;
-        let response = read_object(stream.deref_mut()).await.context("receiving Mavrik response over TCP")?;
-        Ok(response)
+    #[tokio::test]
+    async fn waiting_requests_fail_when_the_connection_closes() -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (client, stream) = tokio::join!(connect(&listener), accept(&listener, welcome));
+        let (client, mut stream) = (client?, stream?);
+
+        let request = MavrikRequest::GetTask { task_id: TaskId::from_parts(1, 0) };
+        let waiting = client.request(&request);
+        let server = async {
+            read_request(&mut stream).await?;
+            drop(stream);
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let (waiting, served) = tokio::join!(waiting, server);
+        served?;
+        assert!(waiting.is_err());
+        assert!(client.request(&request).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undecodable_responses_only_fail_their_own_request() -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (client, stream) = tokio::join!(connect(&listener), accept(&listener, welcome));
+        let (client, mut stream) = (client?, stream?);
+
+        let garbled = client.request(&MavrikRequest::GetStoreState);
+        let intact = client.request(&MavrikRequest::ListDeadTasks);
+        let server = async {
+            for _ in 0..2 {
+                let request = read_request(&mut stream).await?;
+                if request.message == MavrikRequest::GetStoreState {
+                    let response = json!({"correlation_id": request.correlation_id, "message": {"type": "bogus"}});
+                    write_object(&mut stream, Encoding::MessagePack, &response).await?;
+                } else {
+                    let message = MavrikResponse::Count(1);
+                    let response = Envelope { correlation_id: request.correlation_id, message };
+                    write_object(&mut stream, Encoding::MessagePack, &response).await?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let (garbled, intact, served) = tokio::join!(garbled, intact, server);
+        served?;
+        assert!(garbled.is_err());
+        assert!(matches!(intact?, MavrikResponse::Count(1)));
+        assert!(client.waiters.lock().unwrap().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connecting_fails_on_an_incompatible_server() -> Result<(), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let newer = |hello: &Hello| Welcome { protocol_version: PROTOCOL_VERSION + 1, ..welcome(hello) };
+
+        let (client, stream) = tokio::join!(connect(&listener), accept(&listener, newer));
+        stream?;
+        let error = client.unwrap_err().to_string();
+        assert!(error.contains(&format!("speaks protocol version {}", PROTOCOL_VERSION + 1)), "{error}");
+        Ok(())
     }
 }
@@ -1,28 +1,175 @@
-use crate::io::{read_object, write_object};
-use crate::messaging::{MavrikRequest, MavrikResponse, Task, TaskId};
+use crate::executor::ExecutorCommand;
+use crate::io::{decode, read_frame, read_object, write_object, Encoding, ProtocolError};
+use crate::messaging::{
+    CancelState, Capabilities, Envelope, ErrorCode, Hello, MavrikRequest, MavrikResponse, Task, TaskId, TaskResult,
+    Welcome, PROTOCOL_VERSION,
+};
 use crate::service::ServiceTask;
-use crate::store::{PullStore, PushStore, QueryStore};
-use anyhow::Context;
-use log::trace;
+use crate::scheduler;
+use crate::store::{Cancellation, PullStore, PushStore, QueryStore, ScheduleStore};
+use anyhow::{anyhow, bail, Context};
+use futures::FutureExt;
+use log::{info, trace, warn};
+use serde::de::IgnoredAny;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+/// The most requests from one connection processed at once. Further requests are rejected as overloaded until one
+/// finishes.
+const MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// The correlation ID of error responses to frames that couldn't be read, so have no ID of their own.
+const UNCORRELATED: u64 = 0;
 
 pub struct TcpClientHandler<Store> {
-    stream: TcpStream,
-    store: Store,
+    writer: OwnedWriteHalf,
+    reader: JoinHandle<()>,
+    events_rx: mpsc::Receiver<HandlerEvent>,
+    max_frame_size: u32,
+    encoding: Encoding,
+    processor: RequestProcessor<Store>,
+    in_progress: JoinSet<(u64, Result<MavrikResponse, RequestError>)>,
+}
+
+/// What the client handler is ready to act on.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    /// The client's handshake was read from the connection, always before any request.
+    Hello(Result<Hello, anyhow::Error>),
+
+    /// A request was read from the connection, or couldn't be and should be answered with an error.
+    Request(u64, Result<MavrikRequest, anyhow::Error>),
+
+    /// Reading from the connection failed, so no more requests can be read.
+    Disconnected(anyhow::Error),
+
+    /// A request finished processing and its response can be sent.
+    Response(u64, Result<MavrikResponse, RequestError>),
+}
+
+/// A failed request, along with the code reported to the client.
+#[derive(Debug)]
+pub struct RequestError {
+    code: ErrorCode,
+    error: anyhow::Error,
+}
+
+impl RequestError {
+    fn new(code: ErrorCode, error: anyhow::Error) -> Self {
+        Self { code, error }
+    }
+}
+
+impl From<anyhow::Error> for RequestError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(ErrorCode::Internal, error)
+    }
+}
+
+/// Tags the error of a result with the code reported to the client.
+trait WithErrorCode<T> {
+    fn code(self, code: ErrorCode) -> Result<T, RequestError>;
+}
+
+impl<T, E> WithErrorCode<T> for Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn code(self, code: ErrorCode) -> Result<T, RequestError> {
+        self.map_err(|e| RequestError::new(code, e.into()))
+    }
 }
 
 impl<Store> TcpClientHandler<Store>
 where
     Store: PushStore<Id = TaskId, Error = anyhow::Error>
         + PullStore<Id = TaskId, Error = anyhow::Error>
-        + QueryStore<Error = anyhow::Error>
+        + QueryStore<Id = TaskId, Error = anyhow::Error>
+        + ScheduleStore<Id = TaskId, Error = anyhow::Error>
         + Clone
         + Send
         + Sync
         + 'static,
 {
-    pub fn new(stream: TcpStream, store: Store) -> Self {
-        Self { stream, store }
+    pub fn new(
+        stream: TcpStream,
+        store: Store,
+        commands_tx: mpsc::UnboundedSender<ExecutorCommand>,
+        max_frame_size: u32,
+    ) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (events_tx, events_rx) = mpsc::channel(1);
+        let reader = tokio::spawn(read_requests(reader, events_tx, max_frame_size));
+        let processor = RequestProcessor { store, commands_tx };
+
+        Self {
+            writer,
+            reader,
+            events_rx,
+            max_frame_size,
+            encoding: Encoding::Json,
+            processor,
+            in_progress: JoinSet::new(),
+        }
+    }
+}
+
+impl<Store> TcpClientHandler<Store> {
+    /// Answer the client's handshake, then fail if the client can't be served.
+    async fn welcome(&mut self, hello: Hello) -> Result<(), anyhow::Error> {
+        let capabilities = Capabilities {
+            max_frame_size: self.max_frame_size,
+            max_concurrent_requests: MAX_CONCURRENT_REQUESTS,
+        };
+        let welcome = Welcome::new(&hello, capabilities);
+        write_object(&mut self.writer, Encoding::Json, &welcome)
+            .await
+            .context("sending Welcome over TCP failed")?;
+
+        let Hello { protocol_version, mavrik_version, encodings } = hello;
+        if protocol_version != PROTOCOL_VERSION {
+            bail!(
+                "client running Mavrik {mavrik_version} speaks protocol version {protocol_version}, \
+                but this server speaks version {PROTOCOL_VERSION}"
+            );
+        }
+        let Some(encoding) = welcome.encoding else {
+            bail!("client running Mavrik {mavrik_version} supports none of this server's encodings: {encodings:?}");
+        };
+
+        self.encoding = encoding;
+        info!(mavrik_version, encoding:?; "Client connected");
+        Ok(())
+    }
+
+    /// Send the response to a request, or the error it failed with.
+    async fn respond(
+        &mut self,
+        correlation_id: u64,
+        response: Result<MavrikResponse, RequestError>,
+    ) -> Result<(), anyhow::Error> {
+        let message = response.unwrap_or_else(|RequestError { code, error }| {
+            warn!(correlation_id, code:?, error:%; "Request failed");
+            MavrikResponse::Error { code, message: format!("{error:#}") }
+        });
+
+        let response = Envelope { correlation_id, message };
+        trace!(response:?; "Sending response over TCP");
+        write_object(&mut self.writer, self.encoding, &response)
+            .await
+            .context("sending response over TCP failed")
+    }
+}
+
+impl<Store> Drop for TcpClientHandler<Store> {
+    fn drop(&mut self) {
+        self.reader.abort();
     }
 }
 
@@ -30,41 +177,368 @@ impl<Store> ServiceTask for TcpClientHandler<Store>
 where
     Store: PushStore<Id = TaskId, Error = anyhow::Error>
         + PullStore<Id = TaskId, Error = anyhow::Error>
-        + QueryStore<Error = anyhow::Error>
+        + QueryStore<Id = TaskId, Error = anyhow::Error>
+        + ScheduleStore<Id = TaskId, Error = anyhow::Error>
         + Clone
         + Send
         + Sync
         + 'static,
 {
-    type ReadyTask = Result<MavrikRequest, anyhow::Error>;
+    type ReadyTask = HandlerEvent;
 
     async fn poll_task(&mut self) -> Self::ReadyTask {
-        read_object(&mut self.stream)
-            .await
-            .context("receiving Mavrik request over TCP failed")
+        select! {
+            event = self.events_rx.recv() => {
+                event.unwrap_or_else(|| HandlerEvent::Disconnected(anyhow!("TCP reader stopped")))
+            }
+
+            Some(result) = self.in_progress.join_next() => match result {
+                Ok((correlation_id, response)) => HandlerEvent::Response(correlation_id, response),
+                Err(e) => {
+                    HandlerEvent::Response(UNCORRELATED, Err(anyhow!(e).context("joining request failed").into()))
+                }
+            }
+        }
+    }
+
+    async fn on_task_ready(&mut self, event: Self::ReadyTask) -> Result<(), anyhow::Error> {
+        let (correlation_id, message) = match event {
+            HandlerEvent::Hello(Ok(hello)) => return self.welcome(hello).await,
+            HandlerEvent::Hello(Err(e)) => return Err(e.context("receiving Hello over TCP failed")),
+            HandlerEvent::Response(correlation_id, response) => return self.respond(correlation_id, response).await,
+            HandlerEvent::Disconnected(e) => return Err(e.context("receiving Mavrik request over TCP failed")),
+            HandlerEvent::Request(correlation_id, Ok(message)) => (correlation_id, message),
+            HandlerEvent::Request(correlation_id, Err(e)) => {
+                let error = RequestError::new(ErrorCode::InvalidRequest, e);
+                return self.respond(correlation_id, Err(error)).await;
+            }
+        };
+
+        if self.in_progress.len() >= MAX_CONCURRENT_REQUESTS {
+            let error = anyhow!("too many requests in progress, at most {MAX_CONCURRENT_REQUESTS} are allowed");
+            return self.respond(correlation_id, Err(RequestError::new(ErrorCode::Overloaded, error))).await;
+        }
+
+        let processor = self.processor.clone();
+        self.in_progress.spawn(async move {
+            let response = AssertUnwindSafe(processor.process(message))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("request processing panicked").into()));
+            (correlation_id, response)
+        });
+        Ok(())
+    }
+}
+
+/// Read the client's handshake and then its requests from the connection until it fails.
+///
+/// Oversized frames are skipped and malformed frames are read in full, so they're answered with errors and the next
+/// frame is read as usual.
+///
+async fn read_requests(mut reader: OwnedReadHalf, events_tx: mpsc::Sender<HandlerEvent>, max_frame_size: u32) {
+    let hello: Result<Hello, _> = read_object(&mut reader, Encoding::Json, max_frame_size).await;
+    let encoding = hello.as_ref().ok().and_then(Hello::encoding);
+    if events_tx.send(HandlerEvent::Hello(hello)).await.is_err() {
+        return;
     }
+    // The handler fails the connection without an encoding in common.
+    let Some(encoding) = encoding else {
+        return;
+    };
+
+    loop {
+        let event = match read_frame(&mut reader, max_frame_size).await {
+            Ok(payload) => decode_request(encoding, &payload),
+            Err(e) if matches!(e.downcast_ref(), Some(ProtocolError::FrameTooLarge { .. })) => {
+                HandlerEvent::Request(UNCORRELATED, Err(e))
+            }
+            Err(e) => {
+                let _ = events_tx.send(HandlerEvent::Disconnected(e)).await;
+                break;
+            }
+        };
+
+        if events_tx.send(event).await.is_err() {
+            break;
+        }
+    }
+}
 
-    async fn on_task_ready(&mut self, request: Self::ReadyTask) -> Result<(), anyhow::Error> {
-        let response = match request? {
+/// Decode a request from a frame's payload, answering malformed requests under their own ID if it can be read.
+fn decode_request(encoding: Encoding, payload: &[u8]) -> HandlerEvent {
+    match decode::<Envelope<MavrikRequest>>(encoding, payload) {
+        Ok(Envelope { correlation_id, message }) => HandlerEvent::Request(correlation_id, Ok(message)),
+        Err(e) => {
+            let correlation_id = decode::<Envelope<IgnoredAny>>(encoding, payload)
+                .map_or(UNCORRELATED, |envelope| envelope.correlation_id);
+            HandlerEvent::Request(correlation_id, Err(e.context("deserializing request")))
+        }
+    }
+}
+
+/// Processes requests from a client against the store and executor.
+#[derive(Clone)]
+struct RequestProcessor<Store> {
+    store: Store,
+    commands_tx: mpsc::UnboundedSender<ExecutorCommand>,
+}
+
+impl<Store> RequestProcessor<Store>
+where
+    Store: PushStore<Id = TaskId, Error = anyhow::Error>
+        + PullStore<Id = TaskId, Error = anyhow::Error>
+        + QueryStore<Id = TaskId, Error = anyhow::Error>
+        + ScheduleStore<Id = TaskId, Error = anyhow::Error>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    async fn process(self, request: MavrikRequest) -> Result<MavrikResponse, RequestError> {
+        let response = match request {
             MavrikRequest::NewTask { queue, payload } => {
+                let due_at = payload.due_at();
                 let task = Task::from(payload);
-                let task_id = self
-                    .store
-                    .push(&queue, task)
-                    .await
-                    .context("store push failed")?;
+                let task_id = match due_at {
+                    Some(run_at) => self
+                        .store
+                        .schedule(&queue, task, run_at)
+                        .await
+                        .context("store schedule failed")
+                        .code(ErrorCode::StoreUnavailable)?,
+                    None => self
+                        .store
+                        .push(&queue, task)
+                        .await
+                        .context("store push failed")
+                        .code(ErrorCode::StoreUnavailable)?,
+                };
                 MavrikResponse::NewTaskId(task_id)
             }
 
+            MavrikRequest::AwaitResult { task_id, timeout: wait } => {
+                let pull = self.store.pull(task_id);
+                let result = match wait {
+                    Some(wait) => timeout(Duration::from_millis(wait), pull).await.ok(),
+                    None => Some(pull.await),
+                };
+
+                match result {
+                    Some(result) => {
+                        let result = result.context("store pull failed").code(ErrorCode::StoreUnavailable)?;
+                        MavrikResponse::TaskResult(result)
+                    },
+                    None => MavrikResponse::TimedOut,
+                }
+            }
+
+            MavrikRequest::GetTask { task_id } => {
+                let task = self.store.task(task_id).await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Task(task)
+            }
+
+            MavrikRequest::CancelTask { task_id } => {
+                let cancellation = self.store
+                    .cancel(task_id, TaskResult::Cancelled)
+                    .await
+                    .code(ErrorCode::StoreUnavailable)?;
+                let state = match cancellation {
+                    Cancellation::Cancelled => CancelState::Cancelled,
+                    Cancellation::Processing => {
+                        self.commands_tx
+                            .send(ExecutorCommand::CancelTask(task_id))
+                            .context("sending task cancellation to executor failed")?;
+                        CancelState::Cancelling
+                    }
+                    Cancellation::NotFound => CancelState::NotFound,
+                };
+                MavrikResponse::Cancellation(state)
+            }
+
             MavrikRequest::GetStoreState => {
-                let state = self.store.state().await?;
+                let state = self.store.state().await.code(ErrorCode::StoreUnavailable)?;
                 MavrikResponse::StoreState(state)
             }
+
+            MavrikRequest::ListDeadTasks => {
+                let dead_tasks = self.store.dead_tasks().await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::DeadTasks(dead_tasks)
+            }
+
+            MavrikRequest::RequeueDeadTasks { task_ids } => {
+                let count = self.store.requeue_dead(task_ids).await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Count(count)
+            }
+
+            MavrikRequest::PurgeDeadTasks { task_ids } => {
+                let count = self.store.purge_dead(task_ids).await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Count(count)
+            }
+
+            MavrikRequest::ListSchedules => {
+                let schedules = self.store.schedules().await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Schedules(schedules)
+            }
+
+            MavrikRequest::AddSchedule { schedule } => {
+                scheduler::validate(&schedule).code(ErrorCode::InvalidRequest)?;
+                let schedule = self.store.save_schedule(schedule).await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Schedule(schedule)
+            }
+
+            MavrikRequest::RemoveSchedule { name } => {
+                let removed = self.store.remove_schedule(&name).await.code(ErrorCode::StoreUnavailable)?;
+                MavrikResponse::Count(removed as usize)
+            }
+
+            MavrikRequest::GetPoolState => {
+                let (reply, pool) = oneshot::channel();
+                self.commands_tx
+                    .send(ExecutorCommand::GetPoolState { reply })
+                    .context("requesting pool state from executor failed")?;
+                MavrikResponse::PoolState(pool.await.context("executor stopped")?)
+            }
+
+            MavrikRequest::ResizePool { min_threads, max_threads } => {
+                let max_threads = max_threads.unwrap_or(min_threads);
+                if min_threads == 0 || max_threads < min_threads {
+                    let error = anyhow!("invalid pool size: min_threads must be at least 1 and at most max_threads");
+                    return Err(RequestError::new(ErrorCode::InvalidRequest, error));
+                }
+
+                let (reply, pool) = oneshot::channel();
+                self.commands_tx
+                    .send(ExecutorCommand::ResizePool { min_threads, max_threads, reply })
+                    .context("sending pool resize to executor failed")?;
+                MavrikResponse::PoolState(pool.await.context("executor stopped")?)
+            }
         };
 
-        trace!(response:?; "Sending response over TCP");
-        write_object(&mut self.stream, &response)
-            .await
-            .context("sending response over TCP failed")
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::DEFAULT_MAX_FRAME_SIZE;
+    use crate::messaging::NewTask;
+    use crate::service::{ServiceChannel, Services};
+    use crate::store::{ProcessStore, TasksInMemory};
+    use serde::Serialize;
+    use std::fmt::Debug;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    async fn connect() -> Result<(TcpStream, ServiceChannel), anyhow::Error> {
+        let (client, channel, _) = connect_to(TasksInMemory::new()).await?;
+        Ok((client, channel))
+    }
+
+    async fn connect_to(
+        store: TasksInMemory,
+    ) -> Result<(TcpStream, ServiceChannel, mpsc::UnboundedReceiver<ExecutorCommand>), anyhow::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, _) = listener.accept().await?;
+
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let handler = TcpClientHandler::new(stream, store, commands_tx, DEFAULT_MAX_FRAME_SIZE);
+        let service = Services::start("TCP-handler", handler);
+        tokio::spawn(service.task);
+        Ok((client, service.channel, commands_rx))
+    }
+
+    async fn hello(client: &mut TcpStream, hello: Hello) -> Result<Welcome, anyhow::Error> {
+        write_object(&mut *client, Encoding::Json, hello).await?;
+        read_object(client, Encoding::Json, DEFAULT_MAX_FRAME_SIZE).await
+    }
+
+    async fn request(client: &mut TcpStream, message: impl Serialize + Debug) -> Result<MavrikResponse, anyhow::Error> {
+        write_object(&mut *client, Encoding::MessagePack, Envelope { correlation_id: 7, message }).await?;
+        let response: Envelope<MavrikResponse> =
+            read_object(client, Encoding::MessagePack, DEFAULT_MAX_FRAME_SIZE).await?;
+        assert_eq!(response.correlation_id, 7);
+        Ok(response.message)
+    }
+
+    #[tokio::test]
+    async fn failed_requests_get_error_responses_and_keep_the_connection_open() -> Result<(), anyhow::Error> {
+        let (mut client, _channel) = connect().await?;
+        hello(&mut client, Hello::default()).await?;
+
+        let response = request(&mut client, json!({"type": "launch_rockets"})).await?;
+        assert!(matches!(response, MavrikResponse::Error { code: ErrorCode::InvalidRequest, .. }));
+
+        let response = request(&mut client, json!({"type": "resize_pool", "min_threads": 0})).await?;
+        assert!(matches!(response, MavrikResponse::Error { code: ErrorCode::InvalidRequest, .. }));
+
+        let response = request(&mut client, json!({"type": "get_store_state"})).await?;
+        assert!(matches!(response, MavrikResponse::StoreState(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_tasks_with_args_that_arent_utf8_are_kept_as_they_are() -> Result<(), anyhow::Error> {
+        let (mut client, _channel) = connect().await?;
+        hello(&mut client, Hello::default()).await?;
+
+        let payload = NewTask { args: vec![0x92, 0x01, 0xff].into(), ..NewTask::for_test("Binary") };
+        let response = request(&mut client, MavrikRequest::NewTask { queue: "default".to_string(), payload }).await?;
+        let MavrikResponse::NewTaskId(task_id) = response else {
+            panic!("unexpected response {response:?}");
+        };
+
+        let response = request(&mut client, MavrikRequest::GetTask { task_id }).await?;
+        let MavrikResponse::Task(Some(task)) = response else {
+            panic!("unexpected response {response:?}");
+        };
+        assert_eq!(&*task.args, [0x92, 0x01, 0xff]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_task_reports_whether_it_was_removed_or_is_being_interrupted() -> Result<(), anyhow::Error> {
+        let store = TasksInMemory::new();
+        let (mut client, _channel, mut commands_rx) = connect_to(store.clone()).await?;
+        hello(&mut client, Hello::default()).await?;
+
+        let running = store.push("default", Task::from(NewTask::for_test("Running"))).await?;
+        let (dequeued, _, _): (TaskId, String, Task) = store.dequeue(&["default".to_string()]).await?;
+        assert_eq!(dequeued, running);
+        let queued = store.push("default", Task::from(NewTask::for_test("Queued"))).await?;
+
+        let response = request(&mut client, MavrikRequest::CancelTask { task_id: running }).await?;
+        assert!(matches!(response, MavrikResponse::Cancellation(CancelState::Cancelling)));
+        assert!(matches!(commands_rx.recv().await, Some(ExecutorCommand::CancelTask(id)) if id == running));
+
+        let response = request(&mut client, MavrikRequest::CancelTask { task_id: queued }).await?;
+        assert!(matches!(response, MavrikResponse::Cancellation(CancelState::Cancelled)));
+
+        let response = request(&mut client, MavrikRequest::CancelTask { task_id: TaskId::from_parts(1, 0) }).await?;
+        assert!(matches!(response, MavrikResponse::Cancellation(CancelState::NotFound)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_advertises_the_servers_protocol_and_capabilities() -> Result<(), anyhow::Error> {
+        let (mut client, _channel) = connect().await?;
+
+        let welcome = hello(&mut client, Hello::default()).await?;
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(welcome.capabilities.max_concurrent_requests, MAX_CONCURRENT_REQUESTS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_with_an_incompatible_client_closes_the_connection() -> Result<(), anyhow::Error> {
+        let (mut client, _channel) = connect().await?;
+
+        let welcome = hello(&mut client, Hello { protocol_version: PROTOCOL_VERSION + 1, ..Hello::default() }).await?;
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert!(request(&mut client, json!({"type": "get_store_state"})).await.is_err());
+        Ok(())
     }
 }
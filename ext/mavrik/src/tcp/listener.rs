@@ -1,13 +1,16 @@
+use crate::executor::ExecutorCommand;
+use crate::io::DEFAULT_MAX_FRAME_SIZE;
 use crate::mavrik::MavrikOptions;
 use crate::messaging::TaskId;
 use crate::service::{ServiceTask, ServiceChannel, Services};
-use crate::store::{PullStore, PushStore, QueryStore};
+use crate::store::{PullStore, PushStore, QueryStore, ScheduleStore};
 use crate::tcp::TcpClientHandler;
 use anyhow::Context;
 use libc::{getppid, kill, SIGUSR1};
 use log::{info, warn};
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 
 /// Mavrik's TCP listener struct, used to accept and manage asynchronous connections from clients and send received
@@ -17,6 +20,8 @@ pub struct MavrikTcpListener<Store> {
     store: Store,
     handlers: JoinSet<Result<(), anyhow::Error>>,
     handler_chans: Vec<ServiceChannel>,
+    commands_tx: mpsc::UnboundedSender<ExecutorCommand>,
+    max_frame_size: u32,
 }
 
 impl<Store> MavrikTcpListener<Store> {
@@ -25,16 +30,22 @@ impl<Store> MavrikTcpListener<Store> {
     /// # Arguments
     ///
     /// `options` - Options used for configuration.
-    /// `params` - Parameters used for initialization.
+    /// `store` - The store client requests are served from.
+    /// `commands_tx` - Sends commands from clients, such as cancelling tasks being processed, to the task executor.
     ///
     /// # Returns
     ///
     /// A result containing a new Mavrik TCP listener on OK, otherwise any error that occurred.
     ///
-    pub async fn new(options: &MavrikOptions, store: Store) -> Result<Self, anyhow::Error> {
+    pub async fn new(
+        options: &MavrikOptions,
+        store: Store,
+        commands_tx: mpsc::UnboundedSender<ExecutorCommand>,
+    ) -> Result<Self, anyhow::Error> {
         let host = options.host.as_deref().unwrap_or("127.0.0.1").to_string();
         let port = options.port.unwrap_or(3001);
         let signal_parent_ready = options.signal_parent_ready.unwrap_or(false);
+        let max_frame_size = options.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
 
         let inner = TcpListener::bind(format!("{host}:{port}")).await?;
         let handlers = JoinSet::new();
@@ -54,6 +65,8 @@ impl<Store> MavrikTcpListener<Store> {
             store,
             handlers,
             handler_chans,
+            commands_tx,
+            max_frame_size,
         })
     }
 }
@@ -62,7 +75,8 @@ impl<Store> ServiceTask for MavrikTcpListener<Store>
 where
     Store: PushStore<Id = TaskId, Error = anyhow::Error>
         + PullStore<Id = TaskId, Error = anyhow::Error>
-        + QueryStore<Error = anyhow::Error>
+        + QueryStore<Id = TaskId, Error = anyhow::Error>
+        + ScheduleStore<Id = TaskId, Error = anyhow::Error>
         + Clone
         + Send
         + Sync
@@ -85,7 +99,7 @@ where
 
         let service = Services::start(
             "TCP-handler",
-            TcpClientHandler::new(stream, self.store.clone()),
+            TcpClientHandler::new(stream, self.store.clone(), self.commands_tx.clone(), self.max_frame_size),
         );
 
         self.handlers.spawn(service.task);
@@ -5,6 +5,7 @@ require_relative "mavrik/config"
 require_relative "mavrik/configurable"
 require_relative "mavrik/execute_task"
 require_relative "mavrik/executor"
+require_relative "mavrik/pending_task"
 require_relative "mavrik/task"
 require_relative "mavrik/version"
 require_relative "mavrik/mavrik"
@@ -15,6 +16,33 @@ module Mavrik
 
   class Error < StandardError; end
 
+  # Raised when waiting for a task's result times out.
+  class TimeoutError < Error; end
+
+  # Raised when the value of a failed task is requested.
+  class TaskFailed < Error; end
+
+  # Raised in a task that's cancelled while running, and when the value of a cancelled task is requested.
+  class TaskCancelled < Error; end
+
+  # Raised in a task that runs for longer than its timeout.
+  class TaskTimedOut < Error; end
+
+  # Raised when the server rejects a request as malformed or invalid.
+  class InvalidRequest < Error; end
+
+  # Raised when a request refers to something the server doesn't have.
+  class NotFound < Error; end
+
+  # Raised when the server's store fails to serve a request.
+  class StoreUnavailable < Error; end
+
+  # Raised when the server has too many requests in progress on the connection to take another.
+  class Overloaded < Error; end
+
+  # Raised when the server fails a request for any other reason.
+  class ServerError < Error; end
+
   # The Mavrik client instance.
   # @return [Mavrik::Client] The client instance.
   def self.client
@@ -3,6 +3,8 @@
 require "singleton"
 
 module Mavrik
+  # Makes requests to the Mavrik server. Requests the server fails raise the Mavrik::Error subclass for why, such as
+  # Mavrik::InvalidRequest or Mavrik::StoreUnavailable.
   class Client
     include ::Singleton
 
@@ -15,21 +17,137 @@ module Mavrik
     # @param definition [String] The name of the task to run
     # @param args [Array] The positional arguments to pass to the task
     # @param kwargs [Hash] The keyword arguments to pass to the task
+    # @param queue [String] The name of the queue to submit the task to
+    # @param priority [Integer] The priority of the task within its queue, higher priorities being run first
+    # @param max_attempts [Integer] The number of times to attempt the task before reporting its failure
+    # @param non_retryable [Array<String>] The names of error classes that fail the task without retrying
+    # @param timeout [Numeric, nil] How many seconds each attempt can run for, or nil to use the server's default
+    # @param concurrency_key [String, nil] A key limiting how many tasks sharing it run at once, or nil for no limit
+    # @param concurrency_limit [Integer, nil] How many tasks with the concurrency key can run at once, 1 by default
+    # @param run_at [Time, nil] When to run the task, or nil to run it as soon as possible
+    # @param delay [Numeric, nil] How many seconds to wait before running the task, or nil to run it as soon as possible
     # @return [String] The task ID
-    def new_task(definition:, args:, kwargs:)
+    def new_task(
+      definition:, args:, kwargs:, queue: "default", priority: 0, max_attempts: 1, non_retryable: [], timeout: nil,
+      concurrency_key: nil, concurrency_limit: nil, run_at: nil, delay: nil
+    )
       @conn.request({
         type: :new_task,
-        queue: :default,
+        queue: queue.to_s,
         payload: {
           definition:,
           args: JSON.generate(args),
-          kwargs: JSON.generate(kwargs)
+          kwargs: JSON.generate(kwargs),
+          priority:,
+          max_attempts:,
+          non_retryable: non_retryable.map(&:to_s),
+          timeout: timeout && (timeout * 1000).round,
+          concurrency_key: concurrency_key&.to_s,
+          concurrency_limit:,
+          run_at: run_at && (run_at.to_r * 1000).round,
+          delay: delay && (delay * 1000).round
         }
       })
     end
 
+    # Waits for a task to finish and returns its result.
+    # Successful results are removed from the server once returned.
+    # @param task_id [String] The ID of the task
+    # @param timeout [Numeric, nil] How many seconds to wait, or nil to wait until the task finishes
+    # @return [Hash, nil] The result, with a :type of "success" or "failure", or nil if the wait timed out
+    def await_result(task_id, timeout: nil)
+      @conn.request(type: :await_result, task_id:, timeout: timeout && (timeout * 1000).round)
+    end
+
+    # Looks up a task without waiting for it to finish or taking its result.
+    # @param task_id [String] The ID of the task
+    # @return [Hash, nil] The task's status, queue, attempts, timestamps, and result if finished, or nil if not found
+    def task(task_id)
+      @conn.request(type: :get_task, task_id:)
+    end
+
+    # Cancels a task. Tasks that haven't started are removed from their queue, and running tasks are interrupted.
+    # @param task_id [String] The ID of the task
+    # @return [String] "cancelled" if the task was removed from its queue, "cancelling" if it's running and being
+    #   interrupted, or "not_found" if it had already finished or wasn't found
+    def cancel_task(task_id)
+      @conn.request(type: :cancel_task, task_id:)
+    end
+
     def store_state
       @conn.request(type: :get_store_state)
     end
+
+    # Lists the tasks that failed for good, along with the failures of their final attempts.
+    # @return [Array<Hash>] The dead tasks
+    def dead_tasks
+      @conn.request(type: :list_dead_tasks)
+    end
+
+    # Returns dead tasks to their queues to be attempted again.
+    # @param task_ids [Array<String>, nil] The IDs of the dead tasks to re-enqueue, or nil for all of them
+    # @return [Integer] The number of tasks re-enqueued
+    def requeue_dead_tasks(task_ids = nil)
+      @conn.request(type: :requeue_dead_tasks, task_ids:)
+    end
+
+    # Removes dead tasks for good.
+    # @param task_ids [Array<String>, nil] The IDs of the dead tasks to remove, or nil for all of them
+    # @return [Integer] The number of tasks removed
+    def purge_dead_tasks(task_ids = nil)
+      @conn.request(type: :purge_dead_tasks, task_ids:)
+    end
+
+    # Lists the recurring schedules.
+    # @return [Array<Hash>] The schedules, along with when they last fired in milliseconds since the Unix epoch
+    def schedules
+      @conn.request(type: :list_schedules)
+    end
+
+    # Adds a recurring schedule, or replaces the schedule with the same name.
+    # @param name [String] The unique name of the schedule
+    # @param cron [String] The cron expression the task is submitted on, evaluated in UTC
+    # @param definition [String] The name of the task to run
+    # @param args [Array] The positional arguments to pass to the task
+    # @param kwargs [Hash] The keyword arguments to pass to the task
+    # @param queue [String] The name of the queue to submit the task to
+    # @return [Hash] The schedule
+    def add_schedule(name:, cron:, definition:, args: [], kwargs: {}, queue: "default")
+      @conn.request({
+        type: :add_schedule,
+        schedule: {
+          name: name.to_s,
+          cron:,
+          queue: queue.to_s,
+          definition: definition.to_s,
+          args: JSON.generate(args),
+          kwargs: JSON.generate(kwargs)
+        }
+      })
+    end
+
+    # Removes a recurring schedule.
+    # @param name [String] The name of the schedule to remove
+    # @return [Integer] 1 if the schedule was removed, or 0 if it didn't exist
+    def remove_schedule(name)
+      @conn.request(type: :remove_schedule, name: name.to_s)
+    end
+
+    # Gets the size of the server's pool of threads that run tasks.
+    # @return [Hash] The number of :threads taking tasks, the number of :busy_threads, and the :min_threads and
+    #   :max_threads the pool autoscales between
+    def pool
+      @conn.request(type: :get_pool_state)
+    end
+
+    # Resizes the server's pool of threads that run tasks.
+    # Threads removed from the pool finish the task they're running first.
+    # @param min [Integer] The fewest threads to run
+    # @param max [Integer, nil] The most threads to run, autoscaling with the number of waiting tasks if more than min,
+    #   or nil to keep the pool at min threads
+    # @return [Hash] The pool's new size, as returned by #pool
+    def resize_pool(min:, max: nil)
+      @conn.request(type: :resize_pool, min_threads: Integer(min), max_threads: max && Integer(max))
+    end
   end
 end
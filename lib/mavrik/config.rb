@@ -9,9 +9,73 @@ module Mavrik
     # @!attribute port [Integer] The TCP port of the Mavrik server.
     attr_accessor :port
 
+    # @!attribute max_frame_size [Integer] The largest message, in bytes, the server or client will read. Larger messages
+    #   are rejected. Defaults to 16 MiB.
+    attr_accessor :max_frame_size
+
     # @!attribute rb_thread_count [Integer] The number of Ruby threads to spin up.
     attr_accessor :rb_thread_count
 
+    # @!attribute rb_thread_max_count [Integer] The most Ruby threads to run. If more than rb_thread_count, the pool
+    #   grows when tasks are waiting and shrinks back to rb_thread_count as they're worked through.
+    attr_accessor :rb_thread_max_count
+
+    # @!attribute queues [Array<String>] The names of the queues to process tasks from, in order of preference.
+    attr_accessor :queues
+
+    # @!attribute queue_order [Symbol] The order tasks are pulled from a queue, either :fifo (default) or :lifo.
+    attr_accessor :queue_order
+
+    # @!attribute queue_weights [Hash{Symbol => Integer}] How often each queue is pulled from relative to the others.
+    #   Queues take turns in proportion to their weights, so a busy queue can't starve the rest. Queues without a weight
+    #   get 1. If not set, queues are pulled from in the order they're listed.
+    #   @example
+    #     config.queue_weights = {critical: 5, default: 2, low: 1}
+    attr_accessor :queue_weights
+
+    # @!attribute queue_concurrency [Hash{Symbol => Integer}] The most threads tasks from each queue can occupy at once.
+    #   Queues without a limit can use every thread.
+    #   @example
+    #     config.queue_concurrency = {low: 2}
+    attr_accessor :queue_concurrency
+
+    # @!attribute store [Hash] The backend to store tasks in and its settings.
+    #   The :backend key is one of :memory (default), :file, or :sqlite. The :file and :sqlite backends take a :path
+    #   to store tasks at, and an optional :retention in seconds to keep results that haven't been retrieved.
+    #   @example
+    #     config.store = {backend: :sqlite, path: "tmp/mavrik.sqlite3", retention: 86_400}
+    attr_accessor :store
+
+    # @!attribute lease_timeout [Integer] How long, in seconds, a task can be processed before it's assumed lost and
+    #   enqueued again. Defaults to 300.
+    attr_accessor :lease_timeout
+
+    # @!attribute retry_base_delay [Integer] The longest delay, in seconds, before retrying a task's first failed attempt.
+    #   The delay doubles with each failed attempt. Defaults to 1.
+    attr_accessor :retry_base_delay
+
+    # @!attribute retry_max_delay [Integer] The longest delay, in seconds, before retrying any failed attempt.
+    #   Defaults to 3600.
+    attr_accessor :retry_max_delay
+
+    # @!attribute task_timeout [Integer] How long, in seconds, an attempt of a task can run for before it's interrupted,
+    #   for tasks that don't set their own timeout. Tasks can run indefinitely if not set.
+    attr_accessor :task_timeout
+
+    # @!attribute rate_limits [Hash{String => Hash}] The most tasks of each definition started per period, to stay within
+    #   the quotas of the services they call. Each limit has a :limit on how many tasks start per :period in seconds
+    #   (defaults to 1), and tasks over it wait in the store until they can start without holding up other tasks.
+    #   @example
+    #     config.rate_limits = {"SendSms" => {limit: 10, period: 1}}
+    attr_accessor :rate_limits
+
+    # @!attribute schedules [Array<Hash>] Tasks to submit on recurring cron schedules.
+    #   Each schedule has a unique :name, a :cron expression evaluated in UTC (with an optional leading seconds field),
+    #   the :definition of the task to submit, and optional :args, :kwargs, and :queue (defaults to "default").
+    #   @example
+    #     config.schedules = [{name: "nightly_report", cron: "0 2 * * *", definition: "NightlyReport", args: [7]}]
+    attr_accessor :schedules
+
     # @!attribute signal_parent_ready [Boolean] Whether to signal the parent process when the server is ready to accept connections.
     attr_accessor :signal_parent_ready
 
@@ -19,9 +83,39 @@ module Mavrik
       {}.tap do |h|
         h[:host] = host if host
         h[:port] = port if port
+        h[:max_frame_size] = max_frame_size if max_frame_size
         h[:rb_thread_count] = rb_thread_count if rb_thread_count
+        h[:rb_thread_max_count] = rb_thread_max_count if rb_thread_max_count
+        h[:queues] = queues.map(&:to_s) if queues
+        h[:queue_order] = queue_order.to_s if queue_order
+        h[:queue_weights] = queue_weights.to_h { |q, w| [q.to_s, Integer(w)] } if queue_weights
+        h[:queue_concurrency] = queue_concurrency.to_h { |q, c| [q.to_s, Integer(c)] } if queue_concurrency
+        h[:store] = store.transform_values { |v| v.is_a?(Symbol) ? v.to_s : v } if store
+        h[:lease_timeout] = lease_timeout if lease_timeout
+        h[:retry_base_delay] = retry_base_delay if retry_base_delay
+        h[:retry_max_delay] = retry_max_delay if retry_max_delay
+        h[:task_timeout] = task_timeout if task_timeout
+        h[:rate_limits] = rate_limits.to_h { |d, l| [d.to_s, rate_limit_to_h(**l)] } if rate_limits
+        h[:schedules] = schedules.map { |s| schedule_to_h(**s) } if schedules
         h[:signal_parent_ready] = signal_parent_ready if signal_parent_ready
       end
     end
+
+    private
+
+    def rate_limit_to_h(limit:, period: 1)
+      {limit: Integer(limit), period: Integer(period)}
+    end
+
+    def schedule_to_h(name:, cron:, definition:, args: [], kwargs: {}, queue: "default")
+      {
+        name: name.to_s,
+        cron:,
+        queue: queue.to_s,
+        definition: definition.to_s,
+        args: JSON.generate(args),
+        kwargs: JSON.generate(kwargs)
+      }
+    end
   end
 end
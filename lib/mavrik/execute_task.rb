@@ -6,7 +6,24 @@ module Mavrik
   # Executes a task using the provided arguments.
   # Called natively by the Mavrik task executor.
   class ExecuteTask
-    # @param ctx [Hash] The task context, containing the task definition, arguments, and keyword arguments.
+    # Stops a task running in another thread by raising Mavrik::TaskCancelled in it.
+    # Called natively by the Mavrik task executor.
+    # @param thread [Thread] The thread running the task
+    # @param task_id [String] The ID of the task to cancel, which is ignored if the thread has moved on to another task
+    def self.cancel(thread, task_id)
+      thread.raise(TaskCancelled, "Task #{task_id} was cancelled") if thread[:mavrik_task_id] == task_id
+    end
+
+    # Stops a task that has run for too long in another thread by raising Mavrik::TaskTimedOut in it.
+    # Called natively by the Mavrik task executor.
+    # @param thread [Thread] The thread running the task
+    # @param task_id [String] The ID of the task that timed out, which is ignored if the thread has moved on
+    # @param seconds [Float] How long the task was allowed to run for
+    def self.time_out(thread, task_id, seconds)
+      thread.raise(TaskTimedOut, "Task #{task_id} timed out after #{seconds} seconds") if thread[:mavrik_task_id] == task_id
+    end
+
+    # @param ctx [Hash] The task context, containing the task ID, definition, arguments, and keyword arguments.
     # @return [Hash] Resulting hash of the task execution.
     def call(ctx)
       task_def = resolve(ctx, :definition)
@@ -15,7 +32,7 @@ module Mavrik
       task_kwargs.transform_keys!(&:to_sym)
 
       task_class = Object.const_get(task_def)
-      result = task_class.new.call(*task_args, **task_kwargs)
+      result = interruptible(ctx[:id]) { task_class.new.call(*task_args, **task_kwargs) }
 
       {
         type: :success,
@@ -32,6 +49,22 @@ module Mavrik
 
     private
 
+    # Runs the block so that a cancellation or timeout can only interrupt it, and not the code around it.
+    # An interrupt that arrives just after the block finishes is raised before returning.
+    def interruptible(task_id)
+      Thread.handle_interrupt(interrupts(:never)) do
+        Thread.current[:mavrik_task_id] = task_id
+        Thread.handle_interrupt(interrupts(:immediate)) { yield }
+      ensure
+        Thread.current[:mavrik_task_id] = nil
+      end
+    end
+
+    # The errors raised in a thread to interrupt its task, mapped to when they're handled.
+    def interrupts(timing)
+      {TaskCancelled => timing, TaskTimedOut => timing}
+    end
+
     def resolve(ctx, key)
       value = ctx[key]
       raise Mavrik::Error, "Missing task #{key}" if value.nil?
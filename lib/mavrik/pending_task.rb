@@ -0,0 +1,61 @@
+# frozen_string_literal: true
+
+require "json"
+
+module Mavrik
+  # A task that's been submitted to the server, whose result can be waited for.
+  #
+  # @example
+  #   pending = SayHello.call("Alice", message: "How are you?")
+  #   pending.value(timeout: 5) # => "Hello, Alice! How are you?"
+  #
+  class PendingTask
+    # @return [String] The ID of the task
+    attr_reader :id
+
+    # @param id [String] The ID of the submitted task
+    def initialize(id)
+      @id = id
+    end
+
+    # Looks up the task's current status without waiting for it to finish.
+    # @return [String, nil] One of "scheduled", "enqueued", "processing", "retrying", "completed", or "failed", or nil
+    #   if the task is no longer stored
+    def status
+      Mavrik.client.task(id)&.fetch(:status)
+    end
+
+    # Waits for the task to finish and returns its result as sent by the server.
+    # @param timeout [Numeric, nil] How many seconds to wait, or nil to wait until the task finishes
+    # @return [Hash, nil] The result, with a :type of "success", "failure", or "cancelled", or nil if the wait timed out
+    def result(timeout: nil)
+      Mavrik.client.await_result(id, timeout:)
+    end
+
+    # Waits for the task to finish and returns the value it returned.
+    # @param timeout [Numeric, nil] How many seconds to wait, or nil to wait until the task finishes
+    # @return [Object] The value the task returned
+    # @raise [Mavrik::TimeoutError] If the task didn't finish in time
+    # @raise [Mavrik::TaskFailed] If the task failed
+    # @raise [Mavrik::TaskCancelled] If the task was cancelled
+    def value(timeout: nil)
+      result = result(timeout:)
+      raise TimeoutError, "Task #{id} didn't finish within #{timeout} seconds" if result.nil?
+      raise TaskFailed, "Task #{id} failed with #{result[:class]}: #{result[:message]}" if result[:type] == "failure"
+      raise TaskCancelled, "Task #{id} was cancelled" if result[:type] == "cancelled"
+
+      JSON.parse(result[:result])
+    end
+
+    # Cancels the task, removing it from its queue or interrupting it if it's running.
+    # A running task may still finish before it's interrupted, so check its #result to see whether it stopped.
+    # @return [Boolean] Whether the task was cancelled or is being interrupted, rather than having already finished
+    def cancel
+      Mavrik.client.cancel_task(id) != "not_found"
+    end
+
+    def to_s
+      id
+    end
+  end
+end
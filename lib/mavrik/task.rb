@@ -26,6 +26,63 @@ module Mavrik
     end
 
     module ClassMethods
+      # Set or get the name of the queue the task is submitted to.
+      # @param name [String, Symbol, nil] The name of the queue to use, or nil to get the current queue
+      # @return [String] The name of the queue
+      def queue(name = nil)
+        @queue = name.to_s unless name.nil?
+        @queue || "default"
+      end
+
+      # Set or get the priority of the task within its queue.
+      # Higher priorities run first, and tasks with the same priority run in the order they're submitted.
+      # @param value [Integer, nil] The priority to use, or nil to get the current priority
+      # @return [Integer] The priority, 0 by default
+      def priority(value = nil)
+        @priority = Integer(value) unless value.nil?
+        @priority || 0
+      end
+
+      # Set or get the number of times the task is attempted before its failure is reported.
+      # Failed attempts are retried with an exponential backoff.
+      # @param count [Integer, nil] The maximum number of attempts, or nil to get the current maximum
+      # @return [Integer] The maximum number of attempts
+      def max_attempts(count = nil)
+        @max_attempts = Integer(count) unless count.nil?
+        @max_attempts || 1
+      end
+
+      # Set or get how long each attempt of the task can run for before it's interrupted with Mavrik::TaskTimedOut.
+      # Timed out attempts are retried like any other failure.
+      # @param seconds [Numeric, nil] The timeout to use, or nil to get the current timeout
+      # @return [Numeric, nil] The timeout in seconds, or nil to use the server's default
+      def timeout(seconds = nil)
+        @timeout = seconds unless seconds.nil?
+        @timeout
+      end
+
+      # Limit how many calls of the task run at once when they share a key, such as the record they update.
+      # Calls over the limit wait in their queue until a call with the same key finishes.
+      # @param key [String, Symbol, nil] The key shared by every call, or nil to derive each call's key with the block
+      # @param limit [Integer] How many calls with the same key can run at once
+      # @yieldparam args [Array] The positional arguments the task is called with
+      # @yieldparam kwargs [Hash] The keyword arguments the task is called with
+      # @yieldreturn [String, nil] The call's key, or nil to not limit the call
+      # @example
+      #   concurrency_key { |account_id, **| "account:#{account_id}" }
+      def concurrency_key(key = nil, limit: 1, &block)
+        @concurrency_key = block || key.to_s
+        @concurrency_limit = Integer(limit)
+      end
+
+      # Mark error classes that fail the task immediately, without retrying.
+      # @param error_classes [Array<Class, String>] The error classes to not retry, or none to get the current ones
+      # @return [Array<String>] The names of the error classes that aren't retried
+      def non_retryable(*error_classes)
+        @non_retryable = error_classes.map(&:to_s) unless error_classes.empty?
+        @non_retryable || []
+      end
+
       def pipe
         p = TaskPipe.new(self)
         yield p if block_given?
@@ -35,9 +92,42 @@ module Mavrik
       # Calls the task executor to run the task.
       # @param args [Array] The positional arguments to pass to the task
       # @param kwargs [Hash] The keyword arguments to pass to the task
-      # @return [String] The future object that will contain the result of the task
+      # @return [Mavrik::PendingTask] The submitted task, which can be waited on for its result
       def call(*args, **kwargs)
-        Mavrik.client.new_task(definition: self.name, args:, kwargs:)
+        submit(args, kwargs)
+      end
+
+      # Calls the task executor to run the task after a delay.
+      # @param seconds [Numeric] How long to wait before running the task
+      # @param args [Array] The positional arguments to pass to the task
+      # @param kwargs [Hash] The keyword arguments to pass to the task
+      # @return [Mavrik::PendingTask] The submitted task, which can be waited on for its result
+      def call_in(seconds, *args, **kwargs)
+        submit(args, kwargs, delay: seconds)
+      end
+
+      # Calls the task executor to run the task at a given time.
+      # @param time [Time] When to run the task
+      # @param args [Array] The positional arguments to pass to the task
+      # @param kwargs [Hash] The keyword arguments to pass to the task
+      # @return [Mavrik::PendingTask] The submitted task, which can be waited on for its result
+      def call_at(time, *args, **kwargs)
+        submit(args, kwargs, run_at: time)
+      end
+
+      private
+
+      def submit(args, kwargs, **schedule)
+        task_id = Mavrik.client.new_task(
+          definition: self.name, args:, kwargs:, queue:, priority:, max_attempts:, non_retryable:, timeout:,
+          **concurrency(args, kwargs), **schedule
+        )
+        PendingTask.new(task_id)
+      end
+
+      def concurrency(args, kwargs)
+        key = @concurrency_key.respond_to?(:call) ? @concurrency_key.call(*args, **kwargs) : @concurrency_key
+        key.nil? ? {} : {concurrency_key: key.to_s, concurrency_limit: @concurrency_limit}
       end
     end
 
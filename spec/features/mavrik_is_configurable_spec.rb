@@ -9,14 +9,30 @@ RSpec.describe "Mavrik is configurable" do
     Mavrik.configure do |c|
       c.host = "1.2.3.4"
       c.port = 1212
+      c.max_frame_size = 1_048_576
       c.signal_parent_ready = true
       c.rb_thread_count = 8
+      c.rb_thread_max_count = 16
+      c.queues = [:critical, :default]
+      c.queue_order = :lifo
+      c.lease_timeout = 600
+      c.retry_base_delay = 2
+      c.retry_max_delay = 120
+      c.task_timeout = 30
     end
 
     expect(Mavrik.config.host).to eq("1.2.3.4")
     expect(Mavrik.config.port).to eq(1212)
+    expect(Mavrik.config.max_frame_size).to eq(1_048_576)
     expect(Mavrik.config.signal_parent_ready).to eq(true)
     expect(Mavrik.config.rb_thread_count).to eq(8)
+    expect(Mavrik.config.rb_thread_max_count).to eq(16)
+    expect(Mavrik.config.queues).to eq([:critical, :default])
+    expect(Mavrik.config.queue_order).to eq(:lifo)
+    expect(Mavrik.config.lease_timeout).to eq(600)
+    expect(Mavrik.config.retry_base_delay).to eq(2)
+    expect(Mavrik.config.retry_max_delay).to eq(120)
+    expect(Mavrik.config.task_timeout).to eq(30)
   end
 
   it "raises an error if the Mavrik server/client is not configured" do
@@ -43,6 +59,53 @@ RSpec.describe "Mavrik is configurable" do
     expect(Mavrik.config.to_h).to eq({host: "localhost", port: 1212})
   end
 
+  it "includes the store settings in the hash representation" do
+    Mavrik.configure do |c|
+      c.store = {backend: :sqlite, path: "tmp/mavrik.sqlite3", retention: 60}
+    end
+
+    expect(Mavrik.config.to_h).to eq({store: {backend: "sqlite", path: "tmp/mavrik.sqlite3", retention: 60}})
+  end
+
+  it "includes the queue weights and concurrency limits in the hash representation" do
+    Mavrik.configure do |c|
+      c.queue_weights = {critical: 5, default: 2, low: 1}
+      c.queue_concurrency = {low: 2}
+    end
+
+    expect(Mavrik.config.to_h).to eq({
+      queue_weights: {"critical" => 5, "default" => 2, "low" => 1},
+      queue_concurrency: {"low" => 2}
+    })
+  end
+
+  it "includes the rate limits in the hash representation" do
+    Mavrik.configure do |c|
+      c.rate_limits = {SendSms: {limit: 10}, "SyncCrm" => {limit: 100, period: 60}}
+    end
+
+    expect(Mavrik.config.to_h).to eq({
+      rate_limits: {"SendSms" => {limit: 10, period: 1}, "SyncCrm" => {limit: 100, period: 60}}
+    })
+  end
+
+  it "includes the schedules in the hash representation" do
+    Mavrik.configure do |c|
+      c.schedules = [{name: :nightly_report, cron: "0 2 * * *", definition: "NightlyReport", kwargs: {days: 7}}]
+    end
+
+    expect(Mavrik.config.to_h).to eq({
+      schedules: [{
+        name: "nightly_report",
+        cron: "0 2 * * *",
+        queue: "default",
+        definition: "NightlyReport",
+        args: "[]",
+        kwargs: "{\"days\":7}"
+      }]
+    })
+  end
+
   it "returns an empty hash if no configuration is specified" do
     Mavrik.configure
 
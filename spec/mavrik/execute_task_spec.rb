@@ -58,4 +58,50 @@ RSpec.describe Mavrik::ExecuteTask do
       )
     end
   end
+
+  describe ".cancel" do
+    class SleepyTask
+      def call
+        sleep
+      end
+    end
+
+    let(:ctx) { {id: "task_id", definition: "SleepyTask", args: JSON.generate([]), kwargs: JSON.generate({})} }
+
+    it "interrupts the task running in the given thread" do
+      thread = Thread.new { subject.call(ctx) }
+      Thread.pass until thread[:mavrik_task_id] == "task_id"
+
+      described_class.cancel(thread, "task_id")
+
+      expect(thread.value).to include(type: :failure, class: "Mavrik::TaskCancelled")
+    end
+
+    it "leaves threads running other tasks alone" do
+      thread = Thread.new { subject.call(ctx) }
+      Thread.pass until thread[:mavrik_task_id] == "task_id"
+
+      described_class.cancel(thread, "other_task_id")
+
+      expect(thread.join(0.1)).to be_nil
+      thread.kill
+    end
+  end
+
+  describe ".time_out" do
+    let(:ctx) { {id: "task_id", definition: "SleepyTask", args: JSON.generate([]), kwargs: JSON.generate({})} }
+
+    it "fails the task running in the given thread with a timeout error" do
+      thread = Thread.new { subject.call(ctx) }
+      Thread.pass until thread[:mavrik_task_id] == "task_id"
+
+      described_class.time_out(thread, "task_id", 1.5)
+
+      expect(thread.value).to include(
+        type: :failure,
+        class: "Mavrik::TaskTimedOut",
+        message: "Task task_id timed out after 1.5 seconds"
+      )
+    end
+  end
 end
@@ -0,0 +1,82 @@
+# frozen_string_literal: true
+
+require "rspec_helper"
+
+RSpec.describe Mavrik::PendingTask do
+  subject { described_class.new("task_id") }
+
+  describe "#status" do
+    it "looks up the task's status" do
+      client = instance_double(Mavrik::Client, task: {id: "task_id", status: "processing"})
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect(subject.status).to eq("processing")
+      expect(client).to have_received(:task).with("task_id")
+    end
+
+    it "returns nil if the task is no longer stored" do
+      client = instance_double(Mavrik::Client, task: nil)
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect(subject.status).to be_nil
+    end
+  end
+
+  describe "#cancel" do
+    it "asks the server to cancel the task" do
+      client = instance_double(Mavrik::Client, cancel_task: "cancelled")
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect(subject.cancel).to eq(true)
+      expect(client).to have_received(:cancel_task).with("task_id")
+    end
+
+    it "is true while a running task is being interrupted" do
+      client = instance_double(Mavrik::Client, cancel_task: "cancelling")
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect(subject.cancel).to eq(true)
+    end
+
+    it "is false if the task had already finished" do
+      client = instance_double(Mavrik::Client, cancel_task: "not_found")
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect(subject.cancel).to eq(false)
+    end
+  end
+
+  describe "#value" do
+    it "waits for the result and returns the parsed value" do
+      client = instance_double(Mavrik::Client, await_result: {type: "success", result: JSON.generate([1, 2])})
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      value = subject.value(timeout: 5)
+
+      expect(value).to eq([1, 2])
+      expect(client).to have_received(:await_result).with("task_id", timeout: 5)
+    end
+
+    it "raises an error if the task failed" do
+      result = {type: "failure", class: "ArgumentError", message: "bad input", backtrace: []}
+      client = instance_double(Mavrik::Client, await_result: result)
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect { subject.value }.to raise_error(Mavrik::TaskFailed, /ArgumentError: bad input/)
+    end
+
+    it "raises an error if the task was cancelled" do
+      client = instance_double(Mavrik::Client, await_result: {type: "cancelled"})
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect { subject.value }.to raise_error(Mavrik::TaskCancelled)
+    end
+
+    it "raises an error if the wait timed out" do
+      client = instance_double(Mavrik::Client, await_result: nil)
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      expect { subject.value(timeout: 1) }.to raise_error(Mavrik::TimeoutError)
+    end
+  end
+end
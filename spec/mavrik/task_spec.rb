@@ -16,13 +16,18 @@ RSpec.describe Mavrik::Task do
       client = instance_double(Mavrik::Client, new_task: "task_id")
       allow(Mavrik).to receive(:client).and_return(client)
 
-      task_id = SayHello.call("John", message: "How are you?")
+      pending = SayHello.call("John", message: "How are you?")
 
-      expect(task_id).to eq("task_id")
+      expect(pending.id).to eq("task_id")
       expect(client).to have_received(:new_task).with(
         definition: SayHello.name,
         args: ["John"],
-        kwargs: {message: "How are you?"}
+        kwargs: {message: "How are you?"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil
       )
     end
 
@@ -30,13 +35,185 @@ RSpec.describe Mavrik::Task do
       client = instance_double(Mavrik::Client, new_task: "task_id")
       allow(Mavrik).to receive(:client).and_return(client)
 
-      task_id = SayHello.call
+      pending = SayHello.call
 
-      expect(task_id).to eq("task_id")
+      expect(pending.id).to eq("task_id")
       expect(client).to have_received(:new_task).with(
         definition: SayHello.name,
         args: [],
-        kwargs: {}
+        kwargs: {},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil
+      )
+    end
+
+    it "sends the task to the configured queue" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      email_task = Class.new(SayHello) { queue :email }
+      stub_const("SendEmail", email_task)
+
+      SendEmail.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "SendEmail",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "email",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil
+      )
+    end
+
+    it "sends the configured priority" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      urgent_task = Class.new(SayHello) { priority 10 }
+      stub_const("UrgentTask", urgent_task)
+
+      UrgentTask.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "UrgentTask",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "default",
+        priority: 10,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil
+      )
+    end
+
+    it "sends the configured maximum number of attempts" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      flaky_task = Class.new(SayHello) { max_attempts 5 }
+      stub_const("FlakyTask", flaky_task)
+
+      FlakyTask.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "FlakyTask",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 5,
+        non_retryable: [],
+        timeout: nil
+      )
+    end
+
+    it "sends the configured timeout" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      slow_task = Class.new(SayHello) { timeout 30 }
+      stub_const("SlowTask", slow_task)
+
+      SlowTask.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "SlowTask",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: 30
+      )
+    end
+
+    it "sends the concurrency key derived from the arguments" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      account_task = Class.new(SayHello) { concurrency_key(limit: 2) { |name, **| "account:#{name}" } }
+      stub_const("AccountTask", account_task)
+
+      AccountTask.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "AccountTask",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil,
+        concurrency_key: "account:John",
+        concurrency_limit: 2
+      )
+    end
+
+    it "sends the error classes that aren't retried" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      strict_task = Class.new(SayHello) { non_retryable ArgumentError, "KeyError" }
+      stub_const("StrictTask", strict_task)
+
+      StrictTask.call("John", message: "Hi")
+
+      expect(client).to have_received(:new_task).with(
+        definition: "StrictTask",
+        args: ["John"],
+        kwargs: {message: "Hi"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: ["ArgumentError", "KeyError"],
+        timeout: nil
+      )
+    end
+  end
+
+  describe ".call_in" do
+    it "sends a new task to run after a delay" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+
+      pending = SayHello.call_in(30, "John", message: "Later")
+
+      expect(pending.id).to eq("task_id")
+      expect(client).to have_received(:new_task).with(
+        definition: SayHello.name,
+        args: ["John"],
+        kwargs: {message: "Later"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil,
+        delay: 30
+      )
+    end
+  end
+
+  describe ".call_at" do
+    it "sends a new task to run at a given time" do
+      client = instance_double(Mavrik::Client, new_task: "task_id")
+      allow(Mavrik).to receive(:client).and_return(client)
+      time = Time.now + 3600
+
+      pending = SayHello.call_at(time, "John", message: "Later")
+
+      expect(pending.id).to eq("task_id")
+      expect(client).to have_received(:new_task).with(
+        definition: SayHello.name,
+        args: ["John"],
+        kwargs: {message: "Later"},
+        queue: "default",
+        priority: 0,
+        max_attempts: 1,
+        non_retryable: [],
+        timeout: nil,
+        run_at: time
       )
     end
   end